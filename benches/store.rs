@@ -0,0 +1,62 @@
+//! Throughput and memory of `CompactStore` against the plain `HashMap` it replaced; run with `cargo bench`
+
+use std::collections::HashMap;
+use std::hint::black_box;
+use std::mem::size_of;
+use std::time::Instant;
+
+use rust_coding_test::{Amount, Currency, Transaction, TransactionKind, TransactionState};
+use rust_coding_test::store::{CompactStore, StoredTransaction};
+
+const COUNT: u64 = 10_000_000;
+
+fn stored(id: u64) -> StoredTransaction {
+    let kind = if id % 3 == 0 { TransactionKind::Withdrawal } else { TransactionKind::Deposit };
+    StoredTransaction::new(Transaction::new(id, (id % 65536) as u16, kind, Amount::from_fx4(id as i64 * 37), Currency::default()), TransactionState::Processed)
+}
+
+// pseudo-random ids to look up, as disputes would
+fn lookups() -> Vec<u64> {
+    let mut seed: u64 = 42;
+    (0..COUNT).map(|_| {
+        seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (seed >> 33) % COUNT
+    }).collect()
+}
+
+fn report(name: &str, insert: f64, get: f64, bytes: usize) {
+    println!("{:<12} insert {:>6.1} M/s   get {:>6.1} M/s   {:>5.1} bytes/transaction   {:>6.1} GB at 1B transactions",
+             name, COUNT as f64 / insert / 1e6, COUNT as f64 / get / 1e6, bytes as f64 / COUNT as f64, bytes as f64 / COUNT as f64 * 1e9 / 1e9);
+}
+
+fn main() {
+    let lookups = lookups();
+
+    let start = Instant::now();
+    let mut map = HashMap::new();
+    for id in 0..COUNT {
+        map.insert(id, stored(id));
+    }
+    let insert = start.elapsed().as_secs_f64();
+    let start = Instant::now();
+    for id in &lookups {
+        black_box(map.get(id).map(StoredTransaction::state));
+    }
+    let get = start.elapsed().as_secs_f64();
+    // hashbrown: key, value and a control byte per bucket
+    report("HashMap", insert, get, map.capacity() * (size_of::<(u64, StoredTransaction)>() + 1));
+    drop(map);
+
+    let start = Instant::now();
+    let mut store = CompactStore::new();
+    for id in 0..COUNT {
+        store.insert(stored(id));
+    }
+    let insert = start.elapsed().as_secs_f64();
+    let start = Instant::now();
+    for id in &lookups {
+        black_box(store.get(*id).map(|s| s.state()));
+    }
+    let get = start.elapsed().as_secs_f64();
+    report("CompactStore", insert, get, store.memory_bytes());
+}
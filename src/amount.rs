@@ -7,28 +7,145 @@ use lazy_static::lazy_static;
 use serde::{de, Deserializer, Serializer};
 use serde::{Deserialize, Serialize};
 
-use crate::amount::Error::{Malformed, PrecisionTooHigh};
+use crate::amount::Error::{DivisionByZero, Malformed, Overflow, PrecisionTooHigh};
+use crate::amount::RoundingMode::{Ceiling, Floor, HalfEven, HalfUp, TowardZero};
 
 /// fixed point precision with 4 fraction digits, to act as monetary type
-/// NB: only the operators +-* are implemented!
-/// NB: there is no overflow check, but limit is +-2^49, well within practical limits of monetary types
+/// NB: only +-* are operators; division and allocation are methods (`div`, `div_int`, `allocate`), as they need to round
+/// NB: the operators don't check for overflow, use the `checked_` or `saturating_` variants on untrusted input;
+/// limit is +-2^63 / 10^4, well within practical limits of monetary types
 #[derive(Debug, Clone, Copy, Default, Ord, PartialOrd, Eq, PartialEq)]
 pub struct Amount {
     amount_fx4: i64,
 }
 
+/// how to round a result that can't be represented with 4 fraction digits
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RoundingMode {
+    /// to nearest, ties to the even neighbour (banker's rounding)
+    HalfEven,
+    /// to nearest, ties away from zero
+    HalfUp,
+    TowardZero,
+    Floor,
+    Ceiling,
+}
+
 lazy_static! {
-    pub static ref ZERO: Amount = Amount::new(0, 0);
+    pub static ref ZERO: Amount = Amount { amount_fx4: 0 };
 }
 
 impl Amount {
-    fn new(whole: i64, fraction: u32) -> Amount {
-        return if whole >= 0 {
-            Amount { amount_fx4: whole * 10000 + (fraction as i64) }
+    fn new(whole: i64, fraction: u32) -> Result<Amount, Error> {
+        let whole_fx4 = whole.checked_mul(10000).ok_or(Overflow)?;
+        if whole >= 0 {
+            whole_fx4.checked_add(fraction as i64)
         } else {
-            Amount { amount_fx4: whole * 10000 - (fraction as i64) }
-        };
+            whole_fx4.checked_sub(fraction as i64)
+        }.map(|amount_fx4| Amount { amount_fx4 }).ok_or(Overflow)
+    }
+
+    /// from the raw fixed point representation, in 1/10000 units; for binary formats
+    pub fn from_fx4(amount_fx4: i64) -> Amount { Amount { amount_fx4 } }
+    /// raw fixed point representation, in 1/10000 units
+    pub fn fx4(self) -> i64 { self.amount_fx4 }
+
+    pub fn checked_add(self, rhs: Self) -> Result<Self, Error> {
+        self.amount_fx4.checked_add(rhs.amount_fx4).map(|amount_fx4| Amount { amount_fx4 }).ok_or(Overflow)
+    }
+
+    pub fn checked_sub(self, rhs: Self) -> Result<Self, Error> {
+        self.amount_fx4.checked_sub(rhs.amount_fx4).map(|amount_fx4| Amount { amount_fx4 }).ok_or(Overflow)
+    }
+
+    /// number of significant fraction digits, 0 to 4
+    pub fn precision(self) -> u32 {
+        (0..4).find(|&digits| self.amount_fx4 % 10_i64.pow(4 - digits) == 0).unwrap_or(4)
     }
+
+    // the product of two fx4 values has 8 fraction digits, so it has to be calculated on a wider type before scaling back
+    fn wide_mul(self, rhs: Self) -> i128 {
+        (self.amount_fx4 as i128 * rhs.amount_fx4 as i128) / 10000
+    }
+
+    pub fn checked_mul(self, rhs: Self) -> Result<Self, Error> {
+        Amount::from_wide(Amount::wide_mul(self, rhs))
+    }
+
+    pub fn saturating_add(self, rhs: Self) -> Self {
+        Amount { amount_fx4: self.amount_fx4.saturating_add(rhs.amount_fx4) }
+    }
+
+    pub fn saturating_sub(self, rhs: Self) -> Self {
+        Amount { amount_fx4: self.amount_fx4.saturating_sub(rhs.amount_fx4) }
+    }
+
+    pub fn saturating_mul(self, rhs: Self) -> Self {
+        Amount { amount_fx4: Amount::wide_mul(self, rhs).clamp(i64::MIN as i128, i64::MAX as i128) as i64 }
+    }
+
+    pub fn div_int(self, divisor: i64, mode: RoundingMode) -> Result<Self, Error> {
+        Amount::from_wide(div_rounded(self.amount_fx4 as i128, divisor as i128, mode)?)
+    }
+
+    pub fn div(self, rhs: Self, mode: RoundingMode) -> Result<Self, Error> {
+        Amount::from_wide(div_rounded(self.amount_fx4 as i128 * 10000, rhs.amount_fx4 as i128, mode)?)
+    }
+
+    /// splits into `parts` (nearly) equal amounts, see `allocate_by_ratios`
+    pub fn allocate(self, parts: usize) -> Result<Vec<Self>, Error> {
+        self.allocate_by_ratios(&vec![1; parts])
+    }
+
+    /// splits proportionally to `ratios`, without losing a single unit: shares are rounded toward zero,
+    /// then the remaining units are handed out one by one to the first shares with a non-zero ratio
+    pub fn allocate_by_ratios(self, ratios: &[u32]) -> Result<Vec<Self>, Error> {
+        let total: i128 = ratios.iter().map(|&r| r as i128).sum();
+        if total == 0 { return Err(DivisionByZero); }
+
+        let mut shares: Vec<i128> = ratios.iter().map(|&r| self.amount_fx4 as i128 * r as i128 / total).collect();
+        let mut remainder = self.amount_fx4 as i128 - shares.iter().sum::<i128>();
+        for (share, _) in shares.iter_mut().zip(ratios).filter(|(_, &r)| r > 0) {
+            if remainder == 0 { break; }
+            *share += remainder.signum();
+            remainder -= remainder.signum();
+        }
+        shares.into_iter().map(Amount::from_wide).collect()
+    }
+
+    /// rounds to `digits` fraction digits
+    pub fn round(self, digits: u32, mode: RoundingMode) -> Result<Self, Error> {
+        if digits >= 4 { return Ok(self); }
+        let unit = 10_i128.pow(4 - digits);
+        Amount::from_wide(div_rounded(self.amount_fx4 as i128, unit, mode)? * unit)
+    }
+
+    fn from_wide(amount_fx4: i128) -> Result<Self, Error> {
+        i64::try_from(amount_fx4).map(|amount_fx4| Amount { amount_fx4 }).map_err(|_| Overflow)
+    }
+}
+
+fn div_rounded(dividend: i128, divisor: i128, mode: RoundingMode) -> Result<i128, Error> {
+    if divisor == 0 { return Err(DivisionByZero); }
+    let quotient = dividend / divisor;
+    let remainder = dividend % divisor;
+    if remainder == 0 { return Ok(quotient); }
+
+    let positive = (dividend < 0) == (divisor < 0);
+    let twice_remainder = remainder.abs() * 2;
+    let away_from_zero = match mode {
+        HalfEven => { twice_remainder > divisor.abs() || (twice_remainder == divisor.abs() && quotient % 2 != 0) }
+        HalfUp => { twice_remainder >= divisor.abs() }
+        TowardZero => { false }
+        Floor => { !positive }
+        Ceiling => { positive }
+    };
+
+    Ok(match (away_from_zero, positive) {
+        (false, _) => { quotient }
+        (true, true) => { quotient + 1 }
+        (true, false) => { quotient - 1 }
+    })
 }
 
 impl Add for Amount {
@@ -43,7 +160,7 @@ impl Mul for Amount {
     type Output = Self;
 
     fn mul(self, rhs: Self) -> Self::Output {
-        Amount { amount_fx4: (self.amount_fx4 * rhs.amount_fx4) / 10000 }
+        Amount { amount_fx4: Amount::wide_mul(self, rhs) as i64 }
     }
 }
 
@@ -60,11 +177,19 @@ pub enum Error {
     NoInput,
     Malformed(String),
     PrecisionTooHigh(String),
+    Overflow,
+    DivisionByZero,
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        fmt::Debug::fmt(self, f)
+        match self {
+            Error::NoInput => { write!(f, "no input") }
+            Malformed(s) => { write!(f, "malformed amount: '{}'", s) }
+            PrecisionTooHigh(s) => { write!(f, "precision too high: '{}'", s) }
+            Overflow => { write!(f, "amount out of range") }
+            DivisionByZero => { write!(f, "division by zero") }
+        }
     }
 }
 
@@ -76,18 +201,21 @@ impl FromStr for Amount {
         let whole: i64 = split_input.next().ok_or(Error::NoInput)?.parse().map_err(|_| { Malformed(s.to_string()) })?;
         let fraction_str_opt = split_input.next();
         if fraction_str_opt.is_none() {
-            return Ok(Amount::new(whole, 0));
+            return Amount::new(whole, 0);
         }
 
         let fraction_str = fraction_str_opt.unwrap();
         let fraction_len = fraction_str.len();
 
         let parsed_fraction: u32 = fraction_str.parse().map_err(|_| { Malformed(s.to_string()) })?;
-        return match fraction_len {
+        let amount = match fraction_len {
             0 => { Err(Malformed(s.to_string())) }
-            1..=4 => { Ok(Amount::new(whole, parsed_fraction * 10_u32.pow((4 - fraction_len) as u32))) }
+            1..=4 => { Amount::new(whole, parsed_fraction * 10_u32.pow((4 - fraction_len) as u32)) }
             _ => { Err(PrecisionTooHigh(s.to_string())) }
-        };
+        }?;
+
+        // `whole` loses the sign of e.g. "-0.5"
+        if whole == 0 && s.starts_with('-') { Ok(Amount { amount_fx4: -amount.amount_fx4 }) } else { Ok(amount) }
     }
 }
 
@@ -99,11 +227,13 @@ impl fmt::Display for Amount {
         if fraction == 0 {
             write!(f, "{}", whole)
         } else {
+            // there's no negative zero to carry the sign of e.g. -0.5
+            if whole == 0 && self.amount_fx4 < 0 { write!(f, "-")?; }
             let mut width = 4;
             // get rid of 'ending zeroes'; this is a fraction after all
             while fraction % 10 == 0 {
-                fraction = fraction / 10;
-                width = width - 1;
+                fraction /= 10;
+                width -= 1;
             }
             write!(f, "{}.{:0width$}", whole, fraction)
         }
@@ -131,7 +261,7 @@ impl Serialize for Amount {
 mod tests {
     use std::str::FromStr;
 
-    use crate::amount::Amount;
+    use crate::amount::{Amount, Error, RoundingMode};
 
     #[test]
     fn parse_test() {
@@ -142,6 +272,11 @@ mod tests {
         let neg_balance: Amount = "-12.5".parse().unwrap();
         assert_eq!(neg_balance.amount_fx4, -125000);
         assert_eq!(neg_balance.to_string(), "-12.5");
+
+        let neg_fraction: Amount = "-0.05".parse().unwrap();
+        assert_eq!(neg_fraction.amount_fx4, -500);
+        assert_eq!(neg_fraction.to_string(), "-0.05");
+        assert_eq!(Amount::from_fx4(neg_fraction.fx4()), neg_fraction);
     }
 
     #[test]
@@ -157,11 +292,11 @@ mod tests {
 
     #[test]
     fn new_test() {
-        let balance = Amount::new(24, 4321);
+        let balance = Amount::new(24, 4321).unwrap();
         assert_eq!(balance.amount_fx4, 244321);
         assert_eq!(balance.to_string(), "24.4321");
 
-        let neg_balance = Amount::new(-24, 4321);
+        let neg_balance = Amount::new(-24, 4321).unwrap();
         assert_eq!(neg_balance.amount_fx4, -244321);
         assert_eq!(neg_balance.to_string(), "-24.4321");
     }
@@ -205,4 +340,105 @@ mod tests {
         let new_balance = balance - transaction;
         assert_eq!(new_balance.to_string(), "41.99");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn overflow_test() {
+        let max = Amount { amount_fx4: i64::MAX };
+        let min = Amount { amount_fx4: i64::MIN };
+        let one = Amount::from_str("1").unwrap();
+
+        assert!(matches!(max.checked_add(one), Err(Error::Overflow)));
+        assert!(matches!(min.checked_sub(one), Err(Error::Overflow)));
+        assert!(matches!(max.checked_mul(Amount::from_str("2").unwrap()), Err(Error::Overflow)));
+        assert_eq!(max.saturating_add(one), max);
+        assert_eq!(min.saturating_sub(one), min);
+        assert_eq!(min.saturating_mul(Amount::from_str("2").unwrap()), min);
+
+        assert!(matches!(Amount::from_str("1000000000000000"), Err(Error::Overflow)));
+        assert!(matches!(Amount::from_str("-1000000000000000"), Err(Error::Overflow)));
+    }
+
+    #[test]
+    fn wide_mul_test() {
+        // the fx4 product of these would overflow an i64 before scaling back
+        let balance = Amount::from_str("1000000000").unwrap();
+        let rate = Amount::from_str("1.5").unwrap();
+        assert_eq!((balance * rate).to_string(), "1500000000");
+        assert_eq!(balance.checked_mul(rate).unwrap().to_string(), "1500000000");
+    }
+
+    #[test]
+    fn div_int_test() {
+        let balance = Amount::from_str("10").unwrap();
+        assert_eq!(balance.div_int(3, RoundingMode::HalfEven).unwrap().to_string(), "3.3333");
+        assert_eq!(balance.div_int(3, RoundingMode::Ceiling).unwrap().to_string(), "3.3334");
+        assert_eq!(balance.div_int(-3, RoundingMode::Floor).unwrap().to_string(), "-3.3334");
+        assert_eq!(balance.div_int(-3, RoundingMode::TowardZero).unwrap().to_string(), "-3.3333");
+        assert!(matches!(balance.div_int(0, RoundingMode::HalfEven), Err(Error::DivisionByZero)));
+    }
+
+    #[test]
+    fn rounding_test() {
+        // ties: 0.0005 / 10 is exactly half a unit
+        let tie = Amount::from_str("0.0005").unwrap();
+        assert_eq!(tie.div_int(10, RoundingMode::HalfEven).unwrap().to_string(), "0");
+        assert_eq!(tie.div_int(10, RoundingMode::HalfUp).unwrap().to_string(), "0.0001");
+        let odd_tie = Amount::from_str("0.0015").unwrap();
+        assert_eq!(odd_tie.div_int(10, RoundingMode::HalfEven).unwrap().to_string(), "0.0002");
+        let neg_tie = Amount::from_str("-1.0005").unwrap();
+        assert_eq!(neg_tie.div_int(10, RoundingMode::HalfUp).unwrap().to_string(), "-0.1001");
+        assert_eq!(neg_tie.div_int(10, RoundingMode::HalfEven).unwrap().to_string(), "-0.1");
+        assert_eq!(neg_tie.div_int(10, RoundingMode::Ceiling).unwrap().to_string(), "-0.1");
+        assert_eq!(neg_tie.div_int(10, RoundingMode::Floor).unwrap().to_string(), "-0.1001");
+    }
+
+    #[test]
+    fn div_test() {
+        let balance = Amount::from_str("100").unwrap();
+        let rate = Amount::from_str("3").unwrap();
+        assert_eq!(balance.div(rate, RoundingMode::HalfUp).unwrap().to_string(), "33.3333");
+        assert_eq!(balance.div(Amount::from_str("0.5").unwrap(), RoundingMode::HalfUp).unwrap().to_string(), "200");
+        assert!(matches!(balance.div(Amount::from_str("0").unwrap(), RoundingMode::HalfUp), Err(Error::DivisionByZero)));
+    }
+
+    #[test]
+    fn allocate_test() {
+        let balance = Amount::from_str("0.05").unwrap();
+        let parts: Vec<String> = balance.allocate(3).unwrap().iter().map(|a| a.to_string()).collect();
+        assert_eq!(parts, vec!["0.0167", "0.0167", "0.0166"]);
+
+        let neg_parts: Vec<String> = Amount::from_str("-1").unwrap().allocate(3).unwrap().iter().map(|a| a.to_string()).collect();
+        assert_eq!(neg_parts, vec!["-0.3334", "-0.3333", "-0.3333"]);
+
+        assert!(matches!(balance.allocate(0), Err(Error::DivisionByZero)));
+    }
+
+    #[test]
+    fn allocate_by_ratios_test() {
+        let balance = Amount::from_str("100").unwrap();
+        let parts: Vec<String> = balance.allocate_by_ratios(&[0, 1, 2]).unwrap().iter().map(|a| a.to_string()).collect();
+        assert_eq!(parts, vec!["0", "33.3334", "66.6666"]);
+
+        let sum = balance.allocate_by_ratios(&[7, 3, 3]).unwrap().into_iter().fold(Amount::default(), |a, b| a + b);
+        assert_eq!(sum, balance);
+
+        assert!(matches!(balance.allocate_by_ratios(&[0, 0]), Err(Error::DivisionByZero)));
+    }
+
+    #[test]
+    fn precision_test() {
+        assert_eq!(Amount::from_str("12").unwrap().precision(), 0);
+        assert_eq!(Amount::from_str("12.50").unwrap().precision(), 1);
+        assert_eq!(Amount::from_str("-0.05").unwrap().precision(), 2);
+        assert_eq!(Amount::from_str("0.0001").unwrap().precision(), 4);
+    }
+
+    #[test]
+    fn round_test() {
+        let balance = Amount::from_str("2.345").unwrap();
+        assert_eq!(balance.round(2, RoundingMode::HalfEven).unwrap().to_string(), "2.34");
+        assert_eq!(balance.round(2, RoundingMode::HalfUp).unwrap().to_string(), "2.35");
+        assert_eq!(balance.round(0, RoundingMode::Floor).unwrap().to_string(), "2");
+        assert_eq!(balance.round(4, RoundingMode::Floor).unwrap(), balance);
+    }
+}
@@ -0,0 +1,889 @@
+//! CSV adapters of the engine: parses transactions, writes client balances, the rejects report and the audit trail
+
+use std::{fmt, io, mem, thread};
+use std::any::Any;
+use std::num::ParseIntError;
+use std::sync::mpsc;
+use std::sync::mpsc::SyncSender;
+use std::thread::ScopedJoinHandle;
+
+use csv::{Reader, StringRecord, Writer};
+use serde::{Deserialize, Serialize};
+
+use crate::amount;
+use crate::amount::Amount;
+use crate::checkpoint::Checkpointer;
+use crate::concurrent::ConcurrentLedger;
+use crate::journal;
+use crate::journal::Journal;
+use crate::ledger::{AuditEntry, Balance, Client, Ledger, LedgerConfig, SnapshotError, Transaction, TransactionError, TransactionKind, TransactionKindConversionError};
+use crate::money;
+use crate::money::{Currency, Money};
+use crate::store::TransactionStore;
+use crate::timeline::Timeline;
+
+#[derive(Debug)]
+pub enum Error {
+    Read(csv::Error),
+    Write(io::Error),
+    Parse(String),
+    Journal(journal::Error),
+    Snapshot(SnapshotError),
+    /// error in a single input line
+    Line(u64, Box<Error>),
+    /// a worker thread of `process_transactions_parallel` panicked
+    Worker(String),
+    /// the ledger's `TransactionStore` failed
+    Store(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Read(e) => { write!(f, "read error: {}", e) }
+            Error::Write(e) => { write!(f, "write error: {}", e) }
+            Error::Parse(e) => { write!(f, "parse error: {}", e) }
+            Error::Journal(e) => { write!(f, "{}", e) }
+            Error::Snapshot(e) => { write!(f, "{}", e) }
+            Error::Line(line, e) => { write!(f, "line {}: {}", line, e) }
+            Error::Worker(e) => { write!(f, "worker failed: {}", e) }
+            Error::Store(e) => { write!(f, "transaction store failed: {}", e) }
+        }
+    }
+}
+
+impl From<csv::Error> for Error {
+    fn from(error: csv::Error) -> Self {
+        Error::Read(error)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Write(error)
+    }
+}
+
+impl From<journal::Error> for Error {
+    fn from(error: journal::Error) -> Self {
+        Error::Journal(error)
+    }
+}
+
+impl From<SnapshotError> for Error {
+    fn from(error: SnapshotError) -> Self {
+        Error::Snapshot(error)
+    }
+}
+
+impl From<TransactionKindConversionError> for Error {
+    fn from(error: TransactionKindConversionError) -> Self {
+        Error::Parse(format!("type conversion failed: {}", error))
+    }
+}
+
+impl From<ParseIntError> for Error {
+    fn from(error: ParseIntError) -> Self {
+        Error::Parse(format!("int conversion failed: {}", error))
+    }
+}
+
+impl From<amount::Error> for Error {
+    fn from(error: amount::Error) -> Self {
+        Error::Parse(format!("amount conversion failed: {}", error))
+    }
+}
+
+impl From<money::Error> for Error {
+    fn from(error: money::Error) -> Self {
+        Error::Parse(format!("money conversion failed: {}", error))
+    }
+}
+
+/// an input row: `type, client, tx, amount[, currency[, timestamp]]`; the timestamp is an integer, e.g. Unix time
+#[derive(Debug, Deserialize)]
+pub struct ApiTransaction {
+    #[serde(rename(serialize = "type", deserialize = "type"))]
+    kind: TransactionKind,
+    client: u16,
+    tx: u64,
+    amount: Amount,
+    currency: Option<Currency>,
+    timestamp: Option<u64>,
+}
+
+impl TryFrom<&StringRecord> for ApiTransaction {
+    type Error = Error;
+
+    // FIXME: csv+serde has a bug in `flexible` support: https://github.com/BurntSushi/rust-csv/issues/145 ; for the time being, we'll manually deserialize
+    fn try_from(record: &StringRecord) -> Result<Self, Self::Error> {
+        let transaction = ApiTransaction {
+            kind: record.get(0).ok_or(Error::Parse("kind missing".to_string()))?.parse()?,
+            client: record.get(1).ok_or(Error::Parse("client missing".to_string()))?.parse()?,
+            tx: record.get(2).ok_or(Error::Parse("tx missing".to_string()))?.parse()?,
+            amount: record.get(3).filter(|s| !s.is_empty()).unwrap_or("0").parse()?,
+            currency: record.get(4).filter(|s| !s.is_empty()).map(str::parse).transpose()?,
+            timestamp: record.get(5).filter(|s| !s.is_empty()).map(str::parse).transpose()?,
+        };
+        // sanity check: amount fits the currency's minor unit
+        if let Some(currency) = transaction.currency { Money::new(transaction.amount, currency)?; }
+        Ok(transaction)
+    }
+}
+
+impl From<&ApiTransaction> for Transaction {
+    fn from(transaction: &ApiTransaction) -> Self {
+        Transaction::new(transaction.tx, transaction.client, transaction.kind, transaction.amount, transaction.currency.unwrap_or_default())
+    }
+}
+
+/// an output row: the balance of a client in a single currency
+#[derive(Debug, Serialize)]
+pub struct ApiClient {
+    client: u16,
+    available: Amount,
+    held: Amount,
+    total: Amount,
+    locked: bool,
+    // only present if there was any input with a currency column
+    #[serde(skip_serializing_if = "Option::is_none")]
+    currency: Option<Currency>,
+}
+
+impl ApiClient {
+    pub fn new(client: &Client, currency: Currency, balance: &Balance, with_currency: bool) -> Self {
+        ApiClient {
+            client: client.id(),
+            available: balance.available(),
+            held: balance.held(),
+            total: balance.total(),
+            locked: client.locked(),
+            currency: Some(currency).filter(|_| with_currency),
+        }
+    }
+}
+
+/// a refused transaction, for the rejects report
+#[derive(Debug, Serialize)]
+pub struct ApiReject {
+    line: u64,
+    record: String,
+    error: String,
+    reason: String,
+}
+
+impl ApiReject {
+    pub fn refused(record: &StringRecord, error: &TransactionError) -> Self {
+        ApiReject {
+            line: record.position().map_or(0, |p| p.line()),
+            record: record.iter().collect::<Vec<&str>>().join(","),
+            error: format!("{:?}", error),
+            reason: error.to_string(),
+        }
+    }
+
+    pub fn malformed(line: u64, record: Option<&StringRecord>, error: &Error) -> Self {
+        ApiReject {
+            line,
+            record: record.map(|r| r.iter().collect::<Vec<&str>>().join(",")).unwrap_or_default(),
+            error: "Malformed".to_string(),
+            reason: error.to_string(),
+        }
+    }
+}
+
+/// a status change of a client, for the audit trail
+#[derive(Debug, Serialize)]
+pub struct ApiAudit {
+    tx: u64,
+    client: u16,
+    /// as in the input, e.g. `freeze:debits`
+    #[serde(rename = "type")]
+    kind: String,
+    /// e.g. `frozen:debits`
+    from: String,
+    to: String,
+    /// `amount currency` pairs paid out on close, separated by `;`
+    refunded: String,
+}
+
+impl From<&AuditEntry> for ApiAudit {
+    fn from(entry: &AuditEntry) -> Self {
+        ApiAudit {
+            tx: entry.id(),
+            client: entry.client_id(),
+            kind: entry.kind().to_string(),
+            from: entry.from().to_string(),
+            to: entry.to().to_string(),
+            refunded: entry.refunded().iter().map(|(currency, amount)| format!("{} {}", amount, currency)).collect::<Vec<String>>().join(";"),
+        }
+    }
+}
+
+/// refused transactions are reported to `rejects`, if given.
+/// Malformed lines fail the whole run in `strict` mode, otherwise they are reported to `rejects` and skipped (see `OnMalformed` for a callback);
+/// returns the number of skipped lines
+pub fn process_transactions<R: io::Read, W: io::Write, E: io::Write>(reader: &mut Reader<R>, wtr: &mut Writer<W>, rejects: Option<&mut Writer<E>>, strict: bool) -> Result<u64, Error> {
+    let mut ledger = Ledger::new();
+    let skipped = apply_transactions(&mut ledger, reader, rejects, strict, Recorders::default())?;
+    write_clients(&ledger, wtr)?;
+    Ok(skipped)
+}
+
+/// notified of every malformed line skipped outside of `strict` mode, with its line number
+pub type OnMalformed<'a> = &'a mut dyn FnMut(u64, &Error);
+
+/// optional recorders of an `apply_transactions` run
+#[derive(Default)]
+pub struct Recorders<'a> {
+    /// accepted transactions are appended to the journal, and input lines it already covers are skipped: after a crash,
+    /// open the journal onto an empty ledger and run again with the same input to continue where it stopped
+    pub journal: Option<&'a mut Journal>,
+    /// periodically saves the whole state with the input position
+    pub checkpoints: Option<&'a mut Checkpointer>,
+    /// keeps accepted transactions for queries of past states
+    pub timeline: Option<&'a mut Timeline>,
+    pub malformed: Option<OnMalformed<'a>>,
+}
+
+
+/// applies the records of `reader` to `ledger`, see `process_transactions`
+pub fn apply_transactions<R: io::Read, E: io::Write, S: TransactionStore>(ledger: &mut Ledger<S>, reader: &mut Reader<R>, mut rejects: Option<&mut Writer<E>>, strict: bool, recorders: Recorders) -> Result<u64, Error> {
+    let Recorders { mut journal, mut checkpoints, mut timeline, mut malformed } = recorders;
+    let mut skipped = 0;
+
+    for result in reader.records() {
+        let line = match &result { Ok(record) => { record.position() } Err(error) => { error.position() } }.map_or(0, |p| p.line());
+        // already journaled before a crash: only malformed and refused lines are reported again
+        let journaled = journal.as_ref().is_some_and(|j| line <= j.last_line());
+        if let (Some(checkpoints), Some(position)) = (checkpoints.as_mut(), result.as_ref().ok().and_then(StringRecord::position)) {
+            checkpoints.tick(position, skipped, ledger, rejects.as_deref_mut())?;
+        }
+
+        let Some((record, api_transaction)) = parse_record(result, rejects.as_deref_mut(), &mut malformed, strict)? else {
+            skipped += 1;
+            continue;
+        };
+        if journaled {
+            if let (Some(rejects), Some(error)) = (rejects.as_mut(), journal.as_ref().and_then(|j| j.refusal(line))) {
+                rejects.serialize(ApiReject::refused(&record, &error))?;
+            }
+            continue;
+        }
+
+        let transaction = (&api_transaction).into();
+        if let Some(timestamp) = api_transaction.timestamp { ledger.advance(timestamp).map_err(Error::Store)?; }
+        match ledger.mutate(transaction) {
+            Ok(_) => {
+                if let Some(journal) = journal.as_mut() { journal.append(line, &transaction)?; }
+                if let Some(timeline) = timeline.as_mut() { timeline.record(line, transaction, ledger).map_err(Error::Store)?; }
+            }
+            Err(TransactionError::Store(kind)) => { return Err(Error::Store(kind.into())); }
+            Err(error) => {
+                if let Some(journal) = journal.as_mut() { journal.refuse(line, error)?; }
+                if let Some(rejects) = rejects.as_mut() { rejects.serialize(ApiReject::refused(&record, &error))?; }
+            }
+        }
+    }
+    if let Some(rejects) = rejects {
+        rejects.flush()?;
+    }
+    if let Some(journal) = journal {
+        journal.sync()?;
+    }
+    Ok(skipped)
+}
+
+// records sent to a worker at once, and batches queued per worker
+const BATCH_SIZE: usize = 1024;
+const QUEUE_DEPTH: usize = 16;
+type Batch = Vec<(StringRecord, Transaction)>;
+
+/// same as `process_transactions`, but applies transactions on `workers` threads: the calling thread parses records and routes
+/// them by client to the workers, each owning a shard of a `ConcurrentLedger`, so the order of every client's transactions is kept.
+/// Transactions of different clients are applied in no particular order, so transaction ids are expected to be unique across clients;
+/// refused transactions are reported to `rejects` in no particular order either. Every shard gets `config`, which can't have a dispute window.
+pub fn process_transactions_parallel<R: io::Read, W: io::Write, E: io::Write>(reader: &mut Reader<R>, wtr: &mut Writer<W>, mut rejects: Option<&mut Writer<E>>,
+                                                                               mut malformed: Option<OnMalformed>, strict: bool, workers: usize, config: LedgerConfig) -> Result<u64, Error> {
+    let ledger = ConcurrentLedger::new(workers).with_config(config);
+    let with_rejects = rejects.is_some();
+
+    let skipped = thread::scope(|scope| -> Result<u64, Error> {
+        let (refused_sender, refused) = mpsc::channel();
+        let (senders, workers): (Vec<SyncSender<Batch>>, Vec<ScopedJoinHandle<()>>) = (0..ledger.shard_count()).map(|_| {
+            let (sender, receiver) = mpsc::sync_channel::<Batch>(QUEUE_DEPTH);
+            let (ledger, refused_sender) = (&ledger, refused_sender.clone());
+            let worker = scope.spawn(move || {
+                for (record, transaction) in receiver.into_iter().flatten() {
+                    if let Err(error) = ledger.mutate(transaction) {
+                        // the receiving end only goes away on error, which is reported anyway
+                        if with_rejects { let _ = refused_sender.send(ApiReject::refused(&record, &error)); }
+                    }
+                }
+            });
+            (sender, worker)
+        }).unzip();
+        drop(refused_sender);
+
+        // consumes the senders, so the workers stop once it returns, whatever the outcome
+        let feed = |senders: Vec<SyncSender<Batch>>| -> Result<u64, Error> {
+            let mut batches: Vec<Batch> = senders.iter().map(|_| Vec::with_capacity(BATCH_SIZE)).collect();
+            let mut skipped = 0;
+            for result in reader.records() {
+                let Some((record, transaction)) = parse_record(result, rejects.as_deref_mut(), &mut malformed, strict)? else {
+                    skipped += 1;
+                    continue;
+                };
+
+                let shard = ledger.shard_of(transaction.client);
+                batches[shard].push((record, (&transaction).into()));
+                if batches[shard].len() == BATCH_SIZE {
+                    // fails only if the worker panicked, which is reported once it's joined
+                    senders[shard].send(mem::replace(&mut batches[shard], Vec::with_capacity(BATCH_SIZE))).map_err(|_| Error::Worker("stopped".to_string()))?;
+                    if let Some(rejects) = rejects.as_mut() {
+                        refused.try_iter().try_for_each(|reject| rejects.serialize(reject))?;
+                    }
+                }
+            }
+            for (sender, batch) in senders.into_iter().zip(batches) {
+                sender.send(batch).map_err(|_| Error::Worker("stopped".to_string()))?;
+            }
+
+            // all senders are gone once the workers are done
+            for reject in refused {
+                if let Some(rejects) = rejects.as_mut() { rejects.serialize(reject)?; }
+            }
+            Ok(skipped)
+        };
+        let fed = feed(senders);
+
+        // every worker is joined, a panic not surfaced this way would fail the whole scope
+        let panics: Vec<Box<dyn Any + Send>> = workers.into_iter().filter_map(|worker| worker.join().err()).collect();
+        if let Some(panic) = panics.first() {
+            let message = panic.downcast_ref::<&str>().copied().or(panic.downcast_ref::<String>().map(String::as_str)).unwrap_or("unknown panic");
+            return Err(Error::Worker(message.to_string()));
+        }
+        fed
+    })?;
+    if let Some(rejects) = rejects {
+        rejects.flush()?;
+    }
+
+    write_clients(&ledger.into_ledger(), wtr)?;
+    Ok(skipped)
+}
+
+/// parses a record; malformed records are skipped with `Ok(None)`, or fail the run in `strict` mode
+fn parse_record<E: io::Write>(result: csv::Result<StringRecord>, rejects: Option<&mut Writer<E>>, malformed: &mut Option<OnMalformed>, strict: bool) -> Result<Option<(StringRecord, ApiTransaction)>, Error> {
+    let record = match result {
+        Ok(record) => { record }
+        // I/O errors are not specific to a line
+        Err(error) if error.is_io_error() => { return Err(error.into()); }
+        Err(error) => {
+            let line = error.position().map_or(0, |p| p.line());
+            skip_malformed(line, None, error.into(), rejects, malformed, strict)?;
+            return Ok(None);
+        }
+    };
+
+    match ApiTransaction::try_from(&record) {
+        Ok(transaction) => { Ok(Some((record, transaction))) }
+        Err(error) => {
+            let line = record.position().map_or(0, |p| p.line());
+            skip_malformed(line, Some(&record), error, rejects, malformed, strict)?;
+            Ok(None)
+        }
+    }
+}
+
+fn skip_malformed<E: io::Write>(line: u64, record: Option<&StringRecord>, error: Error, rejects: Option<&mut Writer<E>>, malformed: &mut Option<OnMalformed>, strict: bool) -> Result<(), Error> {
+    if strict { return Err(Error::Line(line, Box::new(error))); }
+
+    if let Some(malformed) = malformed { malformed(line, &error); }
+    if let Some(rejects) = rejects {
+        rejects.serialize(ApiReject::malformed(line, record, &error))?;
+    }
+    Ok(())
+}
+
+/// writes the balances of `ledger`, one row per client per currency
+pub fn write_clients<W: io::Write, S>(ledger: &Ledger<S>, wtr: &mut Writer<W>) -> Result<(), Error> {
+    let with_currency = ledger.values().flat_map(Client::balances).any(|(currency, _)| *currency != Currency::default());
+    for client in ledger.values() {
+        for (currency, balance) in client.balances() {
+            wtr.serialize(ApiClient::new(client, *currency, balance, with_currency))?;
+        }
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+/// writes every status change of `ledger`, in order
+pub fn write_audit<W: io::Write, S: TransactionStore>(ledger: &Ledger<S>, wtr: &mut Writer<W>) -> Result<(), Error> {
+    for entry in ledger.audit_trail() {
+        wtr.serialize(ApiAudit::from(entry))?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use csv::{ReaderBuilder, Trim, Writer, WriterBuilder};
+
+    use std::{env, fs, io, process};
+
+    use crate::api::{apply_transactions, Error, process_transactions, process_transactions_parallel, Recorders, write_audit, write_clients};
+    use crate::checkpoint::Checkpointer;
+    use crate::journal::{FsyncPolicy, Journal};
+    use crate::timeline::Timeline;
+    use crate::{Ledger, LedgerConfig, LockMode, Retention, WithdrawalDisputes};
+
+    pub fn assert_transaction(data: &str, result: &str) {
+        let mut rdr = ReaderBuilder::new()
+            .trim(Trim::All)
+            .flexible(true)
+            .has_headers(false)
+            .from_reader(data.as_bytes());
+
+        let mut wrt = WriterBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_writer(Vec::new());
+
+        process_transactions(&mut rdr, &mut wrt, None::<&mut Writer<Vec<u8>>>, false).unwrap();
+        let bytes = wrt.into_inner().unwrap();
+
+        // for testing purposes: sort lines to keep result stable
+        let result_string = String::from_utf8(bytes).unwrap();
+        let mut lines: Vec<&str> = result_string.trim().split('\n').collect();
+        lines.sort();
+        let sorted_lines = lines.join("\n");
+
+        assert_eq!(sorted_lines, result.to_string())
+    }
+
+    #[test]
+    pub fn basic() {
+        assert_transaction(
+            concat!(
+            "deposit, 1, 1, 1.0\n",
+            "deposit, 2, 2, 2.0\n",
+            "deposit, 1, 3, 2.0\n",
+            "withdrawal, 1, 4, 1.5\n",
+            "withdrawal, 2, 5, 3.0\n"),
+            concat!(
+            "1,1.5,0,1.5,false\n",
+            "2,2,0,2,false"
+            ),
+        )
+    }
+
+    #[test]
+    pub fn tx_reference_fail() {
+        assert_transaction("deposit, 1, 1, 10\ndispute, 1, 2", "1,10,0,10,false");
+
+        assert_transaction("deposit, 1, 1, 10\nresolve, 1, 2", "1,10,0,10,false");
+
+        assert_transaction("deposit, 1, 1, 10\ndispute,1,1\nresolve,1,2", "1,0,10,10,false");
+    }
+
+    #[test]
+    pub fn dispute() {
+        assert_transaction("deposit, 1, 1, 10\ndispute, 1, 1\n", "1,0,10,10,false");
+        // partial
+        assert_transaction("deposit, 1, 1, 10\ndispute, 1, 1, 2.5\n", "1,7.5,2.5,10,false");
+        assert_transaction("deposit, 1, 1, 10\ndispute, 1, 1, 2.5\ndispute, 1, 1, 4\ndispute, 1, 1, 4\n", "1,3.5,6.5,10,false");
+    }
+
+    #[test]
+    pub fn resolve() {
+        assert_transaction("deposit, 1, 1, 10\ndispute, 1, 1, 2.5\nresolve,1,1", "1,10,0,10,false");
+    }
+
+    #[test]
+    pub fn chargeback() {
+        assert_transaction("deposit, 1, 1, 10\ndispute, 1, 1\nchargeback,1,1", "1,0,0,0,true");
+        assert_transaction("deposit, 1, 1, 10\ndispute, 1, 1, 2.5\nchargeback,1,1,2.5", "1,7.5,0,7.5,true");
+        // part of the disputed amount, the rest stays held
+        assert_transaction("deposit, 1, 1, 10\ndispute, 1, 1, 4\nchargeback,1,1,1", "1,6,3,9,true");
+    }
+
+    #[test]
+    pub fn withdrawal_chargeback() {
+        assert_transaction("deposit, 1, 1, 10\nwithdrawal, 1, 2, 4\ndispute, 1, 2\n", "1,6,4,10,false");
+        assert_transaction("deposit, 1, 1, 10\nwithdrawal, 1, 2, 4\ndispute, 1, 2\nchargeback, 1, 2\n", "1,10,0,10,false");
+    }
+
+    #[test]
+    pub fn locked_user() {
+        assert_transaction("deposit, 1, 1, 10\ndispute, 1, 1, 2.5\nchargeback,1,1,2.5\ndeposit,1,2,5", "1,7.5,0,7.5,true");
+    }
+
+    #[test]
+    pub fn admin() {
+        // reinstated after the chargeback, frozen, then closed with the rest refunded
+        let data = "deposit, 1, 1, 10\ndeposit, 1, 2, 5\ndispute, 1, 2\nchargeback, 1, 2\nunlock, 1, 100\nfreeze, 1, 101\ndeposit, 1, 3, 1\nunlock, 1, 102\nclose, 1, 103\n";
+        assert_transaction(&data[..data.find("freeze").unwrap()], "1,10,0,10,false");
+        assert_transaction(&data[..data.find("unlock, 1, 102").unwrap()], "1,10,0,10,true");
+        assert_transaction(data, "1,0,0,0,true");
+
+        let mut rdr = ReaderBuilder::new().trim(Trim::All).flexible(true).has_headers(false).from_reader(data.as_bytes());
+        let mut ledger = Ledger::new();
+        apply_transactions(&mut ledger, &mut rdr, None::<&mut Writer<Vec<u8>>>, false, Recorders::default()).unwrap();
+        let mut audit = Writer::from_writer(Vec::new());
+        write_audit(&ledger, &mut audit).unwrap();
+        assert_eq!(String::from_utf8(audit.into_inner().unwrap()).unwrap(), concat!(
+            "tx,client,type,from,to,refunded\n",
+            "2,1,chargeback,active,locked:everything,\n",
+            "100,1,unlock,locked:everything,active,\n",
+            "101,1,freeze:everything,active,frozen:everything,\n",
+            "102,1,unlock,frozen:everything,active,\n",
+            "103,1,close,active,closed,10 XXX\n",
+        ));
+
+        // a freeze row can name the client's lock mode
+        assert_transaction("deposit, 1, 1, 10\nfreeze:debits, 1, 100\ndeposit, 1, 2, 5\nwithdrawal, 1, 3, 1", "1,15,0,15,true");
+        assert_transaction("deposit, 1, 1, 10\nfreeze, 1, 100\ndeposit, 1, 2, 5", "1,10,0,10,true");
+        assert!("freeze:nothing".parse::<crate::TransactionKind>().is_err());
+    }
+
+    #[test]
+    pub fn dispute_lifecycle() {
+        // replayed dispute must not hold funds twice
+        assert_transaction("deposit, 1, 1, 10\ndeposit, 1, 2, 5\ndispute, 1, 1\ndispute, 1, 1", "1,5,10,15,false");
+
+        // resolve or chargeback without a dispute
+        assert_transaction("deposit, 1, 1, 10\nresolve, 1, 1", "1,10,0,10,false");
+        assert_transaction("deposit, 1, 1, 10\nchargeback, 1, 1", "1,10,0,10,false");
+
+        // a settled dispute is final
+        assert_transaction("deposit, 1, 1, 10\ndispute, 1, 1\nresolve, 1, 1\nresolve, 1, 1", "1,10,0,10,false");
+        assert_transaction("deposit, 1, 1, 10\ndispute, 1, 1\nresolve, 1, 1\nchargeback, 1, 1", "1,10,0,10,false");
+        assert_transaction("deposit, 1, 1, 10\ndispute, 1, 1\nresolve, 1, 1\ndispute, 1, 1", "1,10,0,10,false");
+    }
+
+    #[test]
+    pub fn cross_client_reference() {
+        assert_transaction(
+            "deposit, 1, 1, 10\ndeposit, 2, 2, 5\ndispute, 2, 1",
+            "1,10,0,10,false\n2,5,0,5,false");
+
+        assert_transaction(
+            "deposit, 1, 1, 10\ndeposit, 2, 2, 5\ndispute, 2, 1\nchargeback, 2, 1",
+            "1,10,0,10,false\n2,5,0,5,false");
+
+        // the owner's dispute can't be settled by someone else either
+        assert_transaction(
+            "deposit, 1, 1, 10\ndeposit, 2, 2, 5\ndispute, 1, 1\nresolve, 2, 1\nchargeback, 2, 1",
+            "1,0,10,10,false\n2,5,0,5,false");
+    }
+
+    #[test]
+    pub fn replayed_file() {
+        let data = "deposit, 1, 1, 10\nwithdrawal, 1, 2, 4\ndeposit, 2, 3, 5\n";
+        assert_transaction(&data.repeat(3), "1,6,0,6,false\n2,5,0,5,false");
+
+        // same id with a different payload is refused
+        assert_transaction("deposit, 1, 1, 10\ndeposit, 1, 1, 20\nwithdrawal, 1, 1, 10", "1,10,0,10,false");
+    }
+
+    #[test]
+    pub fn currency_column() {
+        assert_transaction(
+            "deposit, 1, 1, 10.25, EUR\ndispute, 1, 1, , EUR\ndeposit, 1, 2, 1",
+            "1,0,10.25,10.25,false,EUR\n1,1,0,1,false,XXX");
+    }
+
+    #[test]
+    pub fn multi_currency() {
+        assert_transaction(
+            concat!(
+            "deposit, 1, 1, 10, EUR\n",
+            "deposit, 1, 2, 20, USD\n",
+            "deposit, 2, 3, 5, USD\n",
+            "withdrawal, 1, 4, 15, EUR\n",
+            "withdrawal, 1, 5, 15, USD\n",
+            "dispute, 1, 1\n"),
+            concat!(
+            "1,0,10,10,false,EUR\n",
+            "1,5,0,5,false,USD\n",
+            "2,5,0,5,false,USD"
+            ),
+        );
+
+        // a chargeback locks the client in every currency
+        assert_transaction(
+            "deposit, 1, 1, 10, EUR\ndeposit, 1, 2, 20, USD\ndispute, 1, 1\nchargeback, 1, 1\nwithdrawal, 1, 3, 5, USD",
+            "1,0,0,0,true,EUR\n1,20,0,20,true,USD");
+    }
+
+    #[test]
+    pub fn malformed_currency() {
+        // too precise for EUR, or unknown currency
+        assert_transaction("deposit, 1, 1, 10.255, EUR\ndeposit, 1, 2, 10, XYZ\ndeposit, 1, 3, 1", "1,1,0,1,false");
+    }
+
+    #[test]
+    pub fn rejects() {
+        let mut rdr = ReaderBuilder::new()
+            .trim(Trim::All)
+            .flexible(true)
+            .from_reader("type, client, tx, amount\ndeposit, 1, 1, 10\nwithdrawal, 1, 2, 15\ndispute, 2, 1\n".as_bytes());
+        let mut wrt = WriterBuilder::new().from_writer(Vec::new());
+        let mut rejects = WriterBuilder::new().from_writer(Vec::new());
+
+        process_transactions(&mut rdr, &mut wrt, Some(&mut rejects), false).unwrap();
+
+        assert_eq!(String::from_utf8(rejects.into_inner().unwrap()).unwrap(), concat!(
+        "line,record,error,reason\n",
+        "3,\"withdrawal,1,2,15\",NegativeBalance,insufficient available funds\n",
+        "4,\"dispute,2,1\",ReferencedTransactionClientMismatch,referenced transaction belongs to another client\n",
+        ));
+    }
+
+    #[test]
+    pub fn dispute_window() {
+        // 120 days, in seconds
+        let data = concat!(
+        "type, client, tx, amount, currency, timestamp\n",
+        "deposit, 1, 1, 10, , 0\n",
+        "deposit, 1, 2, 5, , 8640000\n",
+        "dispute, 1, 1, , , 10454400\n",
+        "dispute, 1, 2, , , 10454400\n",
+        "deposit, 1, 3, 1, , 25920000\n",
+        "resolve, 1, 2\n",
+        "deposit, 1, 1, 10\n",
+        "dispute, 1, 4\n",
+        );
+        let mut ledger = Ledger::new().with_config(LedgerConfig { retention: Retention::Time(120 * 86400), ..Default::default() });
+        let mut rdr = ReaderBuilder::new().trim(Trim::All).flexible(true).from_reader(data.as_bytes());
+        let mut rejects = WriterBuilder::new().from_writer(Vec::new());
+        apply_transactions(&mut ledger, &mut rdr, Some(&mut rejects), false, Recorders::default()).unwrap();
+
+        // the disputed deposit outlives its window until resolved
+        assert_eq!(String::from_utf8(rejects.into_inner().unwrap()).unwrap(), concat!(
+        "line,record,error,reason\n",
+        "4,\"dispute,1,1,,,10454400\",DisputeWindowExpired,referenced transaction is past its dispute window\n",
+        "8,\"deposit,1,1,10\",EvictedTransactionId,transaction id is used by a transaction past its dispute window\n",
+        "9,\"dispute,1,4\",ReferencedTransactionNonexistent,referenced transaction does not exist\n",
+        ));
+        assert_eq!(ledger[&1].balance(Default::default()).available(), "16".parse().unwrap());
+    }
+
+    #[test]
+    pub fn lenient() {
+        let data = "type, client, tx, amount\ndeposit, 1, 1, 10\nwithdraw, 1, 2, 5\ndeposit, x, 3, 5\ndeposit, 1, 4, 1.23456\ndeposit, 1, 5, 2\n";
+        let mut rdr = ReaderBuilder::new().trim(Trim::All).flexible(true).from_reader(data.as_bytes());
+        let mut wrt = WriterBuilder::new().has_headers(false).from_writer(Vec::new());
+        let mut rejects = WriterBuilder::new().has_headers(false).from_writer(Vec::new());
+
+        assert_eq!(process_transactions(&mut rdr, &mut wrt, Some(&mut rejects), false).unwrap(), 3);
+
+        assert_eq!(String::from_utf8(wrt.into_inner().unwrap()).unwrap(), "1,12,0,12,false\n");
+        let rejects = String::from_utf8(rejects.into_inner().unwrap()).unwrap();
+        let lines: Vec<&str> = rejects.lines().map(|l| l.split(',').next().unwrap()).collect();
+        assert_eq!(lines, vec!["3", "4", "5"]);
+        assert!(rejects.contains("unknown transaction type: 'withdraw'"));
+
+        // the same lines are passed to the callback
+        let mut rdr = ReaderBuilder::new().trim(Trim::All).flexible(true).from_reader(data.as_bytes());
+        let mut lines = Vec::new();
+        let mut malformed = |line, _: &Error| lines.push(line);
+        apply_transactions(&mut Ledger::new(), &mut rdr, None::<&mut Writer<Vec<u8>>>, false, Recorders { malformed: Some(&mut malformed), ..Default::default() }).unwrap();
+        assert_eq!(lines, vec![3, 4, 5]);
+    }
+
+    #[test]
+    pub fn strict() {
+        let data = "type, client, tx, amount\ndeposit, 1, 1, 10\ndeposit, 1, 2, 1.23456\ndeposit, 1, 3, 2\n";
+        let mut rdr = ReaderBuilder::new().trim(Trim::All).flexible(true).from_reader(data.as_bytes());
+        let mut wrt = WriterBuilder::new().from_writer(Vec::new());
+
+        let error = process_transactions(&mut rdr, &mut wrt, None::<&mut Writer<Vec<u8>>>, true).unwrap_err();
+        assert!(matches!(error, Error::Line(3, _)));
+        assert!(wrt.into_inner().unwrap().is_empty());
+    }
+
+    #[test]
+    pub fn parallel() {
+        // deterministic pseudo-random input with unique transaction ids, with some malformed and refused lines
+        let mut seed: u64 = 7;
+        let mut next = move |bound: u64| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (seed >> 33) % bound
+        };
+        let mut data = String::from("type, client, tx, amount\n");
+        for id in 0..20_000 {
+            let kind = ["deposit", "deposit", "withdrawal", "dispute", "resolve", "chargeback", "bogus"][next(7) as usize];
+            let tx = if id > 0 && ["dispute", "resolve", "chargeback"].contains(&kind) { next(id) } else { id };
+            data += &format!("{}, {}, {}, {}.{}\n", kind, next(50), tx, next(100), next(100));
+        }
+
+        let run = |workers: usize, config: &LedgerConfig| {
+            let mut rdr = ReaderBuilder::new().trim(Trim::All).flexible(true).from_reader(data.as_bytes());
+            let mut wrt = WriterBuilder::new().has_headers(false).from_writer(Vec::new());
+            let mut rejects = WriterBuilder::new().has_headers(false).from_writer(Vec::new());
+            let skipped = match workers {
+                0 => {
+                    let mut ledger = Ledger::new().with_config(config.clone());
+                    let skipped = apply_transactions(&mut ledger, &mut rdr, Some(&mut rejects), false, Recorders::default()).unwrap();
+                    write_clients(&ledger, &mut wrt).unwrap();
+                    skipped
+                }
+                _ => { process_transactions_parallel(&mut rdr, &mut wrt, Some(&mut rejects), None, false, workers, config.clone()).unwrap() }
+            };
+            let mut clients: Vec<String> = String::from_utf8(wrt.into_inner().unwrap()).unwrap().lines().map(str::to_string).collect();
+            clients.sort();
+            // a reference to another client's transaction may be refused for a different reason, depending on timing
+            let mut rejects: Vec<u64> = String::from_utf8(rejects.into_inner().unwrap()).unwrap().lines()
+                .map(|l| l.split(',').next().unwrap().parse().unwrap()).collect();
+            rejects.sort();
+            (skipped, clients, rejects)
+        };
+
+        let refuse = LedgerConfig { withdrawal_disputes: WithdrawalDisputes::Refuse, chargeback_lock: LockMode::Debits, ..Default::default() };
+        for config in [LedgerConfig::default(), refuse] {
+            let (skipped, clients, rejects) = run(0, &config);
+            assert!(skipped > 0 && !rejects.is_empty());
+            for workers in [1, 3, 8] {
+                assert_eq!(run(workers, &config), (skipped, clients.clone(), rejects.clone()));
+            }
+        }
+    }
+
+    #[test]
+    pub fn parallel_strict() {
+        let data = "type, client, tx, amount\ndeposit, 1, 1, 10\ndeposit, 2, 2, 1.23456\ndeposit, 3, 3, 2\n";
+        let mut rdr = ReaderBuilder::new().trim(Trim::All).flexible(true).from_reader(data.as_bytes());
+        let mut wrt = WriterBuilder::new().from_writer(Vec::new());
+
+        let error = process_transactions_parallel(&mut rdr, &mut wrt, None::<&mut Writer<Vec<u8>>>, None, true, 4, LedgerConfig::default()).unwrap_err();
+        assert!(matches!(error, Error::Line(3, _)));
+        assert!(wrt.into_inner().unwrap().is_empty());
+    }
+
+    #[test]
+    pub fn journal_recovery() {
+        let data = "type, client, tx, amount\ndeposit, 1, 1, 10\nwithdrawal, 1, 2, 15\ndeposit, x, 3, 5\ndispute, 1, 1\nresolve, 1, 1\nwithdrawal, 1, 4, 3\n";
+        // returns the skipped lines and the rejects report
+        let apply = |data: &str, ledger: &mut Ledger, journal: Option<&mut Journal>| {
+            let mut rdr = ReaderBuilder::new().trim(Trim::All).flexible(true).from_reader(data.as_bytes());
+            let mut rejects = Writer::from_writer(Vec::new());
+            let skipped = apply_transactions(ledger, &mut rdr, Some(&mut rejects), false, Recorders { journal, ..Default::default() }).unwrap();
+            (skipped, String::from_utf8(rejects.into_inner().unwrap()).unwrap())
+        };
+        let path = env::temp_dir().join(format!("journal-{}-api", process::id()));
+        let _ = fs::remove_file(&path);
+
+        // crash after the dispute: only the first 5 lines made it
+        let mut ledger = Ledger::new();
+        let mut journal = Journal::open(&path, FsyncPolicy::Always, &mut ledger).unwrap();
+        apply(&data[..data.find("resolve").unwrap()], &mut ledger, Some(&mut journal));
+        drop(journal);
+
+        // rerun with the full input: the refused withdrawal and the malformed line are reported again
+        let mut ledger = Ledger::new();
+        let mut journal = Journal::open(&path, FsyncPolicy::Always, &mut ledger).unwrap();
+        assert_eq!(journal.last_line(), 5);
+        assert_eq!(ledger.get(&1).unwrap().balance(Default::default()).held(), "10".parse().unwrap());
+        let recovered = apply(data, &mut ledger, Some(&mut journal));
+        fs::remove_file(&path).unwrap();
+
+        let mut expected = Ledger::new();
+        assert_eq!(recovered, apply(data, &mut expected, None));
+        assert_eq!(recovered.0, 1);
+        assert_eq!(recovered.1.lines().count(), 3);
+        assert_eq!(*ledger, *expected);
+    }
+
+    #[test]
+    pub fn checkpoint_resume() {
+        let data = "type, client, tx, amount\ndeposit, 1, 1, 10\nwithdraw, 1, 2, 15\ndeposit, 2, 3, 5\ndispute, 1, 1\nresolve, 1, 1\nwithdrawal, 1, 4, 3\ndeposit, x, 5, 1\n";
+        let path = env::temp_dir().join(format!("checkpoint-{}-api", process::id()));
+        let _ = fs::remove_file(&path);
+
+        // crash before the resolve, with a checkpoint every 2 records
+        let mut ledger = Ledger::new();
+        let mut rdr = ReaderBuilder::new().trim(Trim::All).flexible(true).from_reader(&data.as_bytes()[..data.find("resolve").unwrap()]);
+        apply_transactions(&mut ledger, &mut rdr, None::<&mut Writer<Vec<u8>>>, false, Recorders { checkpoints: Some(&mut Checkpointer::new(&path, 2)), ..Default::default() }).unwrap();
+
+        // resume with the full input
+        let mut checkpoints = Checkpointer::new(&path, 2);
+        let checkpoint = checkpoints.resume().unwrap().unwrap();
+        assert_eq!(checkpoint.position.line(), 5);
+        assert_eq!(checkpoint.skipped, 1);
+        let mut ledger = checkpoint.ledger;
+        let mut rdr = ReaderBuilder::new().trim(Trim::All).flexible(true).from_reader(io::Cursor::new(data.as_bytes()));
+        rdr.seek(checkpoint.position).unwrap();
+        let skipped = apply_transactions(&mut ledger, &mut rdr, None::<&mut Writer<Vec<u8>>>, false, Recorders { checkpoints: Some(&mut checkpoints), ..Default::default() }).unwrap();
+        assert_eq!(checkpoint.skipped + skipped, 2);
+        checkpoints.finish().unwrap();
+        assert!(!path.exists());
+
+        let mut expected = Ledger::new();
+        let mut rdr = ReaderBuilder::new().trim(Trim::All).flexible(true).from_reader(data.as_bytes());
+        apply_transactions(&mut expected, &mut rdr, None::<&mut Writer<Vec<u8>>>, false, Recorders::default()).unwrap();
+        assert_eq!(*ledger, *expected);
+    }
+
+    #[test]
+    pub fn checkpoint_rejects() {
+        let data = "type, client, tx, amount\ndeposit, 1, 1, 10\nwithdrawal, 1, 2, 15\ndeposit, 2, 3, 5\nwithdrawal, 2, 4, 9\ndeposit, 1, 5, 1\n";
+        let path = env::temp_dir().join(format!("checkpoint-{}-rejects", process::id()));
+        let rejects_path = env::temp_dir().join(format!("checkpoint-{}-rejects.csv", process::id()));
+        let _ = fs::remove_file(&path);
+
+        // crash before the last deposit, after the checkpoint before the second refusal
+        let mut ledger = Ledger::new();
+        let mut rdr = ReaderBuilder::new().trim(Trim::All).flexible(true).from_reader(&data.as_bytes()[..data.find("deposit, 1, 5").unwrap()]);
+        let mut rejects = Writer::from_path(&rejects_path).unwrap();
+        let mut checkpoints = Checkpointer::new(&path, 2).with_rejects(&rejects_path);
+        apply_transactions(&mut ledger, &mut rdr, Some(&mut rejects), false, Recorders { checkpoints: Some(&mut checkpoints), ..Default::default() }).unwrap();
+
+        // the refusal past the checkpoint is dropped, then reported again
+        let checkpoint = checkpoints.resume().unwrap().unwrap();
+        let mut rejects = WriterBuilder::new().has_headers(checkpoint.rejects == 0).from_writer(checkpoint.reopen_rejects(&rejects_path).unwrap());
+        let mut ledger = checkpoint.ledger;
+        let mut rdr = ReaderBuilder::new().trim(Trim::All).flexible(true).from_reader(io::Cursor::new(data.as_bytes()));
+        rdr.seek(checkpoint.position).unwrap();
+        apply_transactions(&mut ledger, &mut rdr, Some(&mut rejects), false, Recorders::default()).unwrap();
+        checkpoints.finish().unwrap();
+
+        let mut expected = WriterBuilder::new().from_writer(Vec::new());
+        process_transactions(&mut ReaderBuilder::new().trim(Trim::All).flexible(true).from_reader(data.as_bytes()), &mut Writer::from_writer(io::sink()), Some(&mut expected), false).unwrap();
+        assert_eq!(fs::read(&rejects_path).unwrap(), expected.into_inner().unwrap());
+        fs::remove_file(&rejects_path).unwrap();
+    }
+
+    #[test]
+    pub fn timeline() {
+        let data = "type, client, tx, amount, currency, timestamp\ndeposit, 1, 1, 10, , 1000\ndeposit, 1, 2, 5, , 1010\ndispute, 1, 1, , , 1020\nchargeback, 1, 1, , , 1030\n";
+        let mut ledger = Ledger::new();
+        let mut timeline = Timeline::new(&ledger, 2).unwrap();
+        let mut rdr = ReaderBuilder::new().trim(Trim::All).flexible(true).from_reader(data.as_bytes());
+        apply_transactions(&mut ledger, &mut rdr, None::<&mut Writer<Vec<u8>>>, false, Recorders { timeline: Some(&mut timeline), ..Default::default() }).unwrap();
+
+        let held = |ledger: Ledger| ledger[&1].balance(Default::default()).held().to_string();
+        // right before the chargeback
+        assert_eq!(held(timeline.at_line(4).unwrap()), "10");
+        assert_eq!(held(timeline.at_timestamp(1029).unwrap()), "10");
+        assert_eq!(held(timeline.at_timestamp(1030).unwrap()), "0");
+        assert_eq!(timeline.at_line(5).unwrap()[&1], ledger[&1]);
+        assert!(timeline.at_timestamp(999).unwrap().is_empty());
+    }
+
+    #[test]
+    pub fn timeline_clock() {
+        // the refused withdrawal moves the clock, so tx 2 is accepted at 200 and still disputable at 300
+        let data = "type, client, tx, amount, currency, timestamp\ndeposit, 1, 1, 10, , 0\nwithdrawal, 1, 3, 50, , 200\ndeposit, 1, 2, 5, ,\ndispute, 1, 2, , , 300\n";
+        let mut ledger = Ledger::new().with_config(LedgerConfig { retention: Retention::Time(120), ..Default::default() });
+        let mut timeline = Timeline::new(&ledger, 100).unwrap();
+        let mut rdr = ReaderBuilder::new().trim(Trim::All).flexible(true).from_reader(data.as_bytes());
+        apply_transactions(&mut ledger, &mut rdr, None::<&mut Writer<Vec<u8>>>, false, Recorders { timeline: Some(&mut timeline), ..Default::default() }).unwrap();
+
+        assert_eq!(ledger[&1].balance(Default::default()).held().to_string(), "5");
+        assert_eq!(timeline.at_line(5).unwrap()[&1], ledger[&1]);
+        assert_eq!(timeline.at_timestamp(200).unwrap()[&1].balance(Default::default()).available().to_string(), "15");
+    }
+}
@@ -0,0 +1,117 @@
+//! Periodic checkpoints of long CSV runs, to resume from after a crash instead of starting over
+//!
+//! A checkpoint file is a JSON header line with the input position, the number of skipped lines so far and the length
+//! of the rejects report, followed by a `Ledger::snapshot` of the state right before that position.
+
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use csv::{Position, Writer};
+use serde::{Deserialize, Serialize};
+
+use crate::api::Error;
+use crate::ledger::{Ledger, SnapshotError};
+use crate::store::TransactionStore;
+
+const VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    version: u32,
+    byte: u64,
+    line: u64,
+    record: u64,
+    skipped: u64,
+    rejects: u64,
+}
+
+/// state of a run right before the record at `position`
+pub struct Checkpoint {
+    pub position: Position,
+    pub skipped: u64,
+    pub ledger: Ledger,
+    /// bytes of the rejects report covering the input before `position`
+    pub rejects: u64,
+}
+
+impl Checkpoint {
+    /// opens the rejects report at `path` to append to, dropping what was written past the checkpoint
+    pub fn reopen_rejects(&self, path: impl AsRef<Path>) -> Result<File, Error> {
+        let file = OpenOptions::new().append(true).create(true).open(path)?;
+        if file.metadata()?.len() < self.rejects { return Err(io::Error::new(ErrorKind::UnexpectedEof, "rejects report is shorter than at the checkpoint").into()); }
+        file.set_len(self.rejects)?;
+        Ok(file)
+    }
+}
+
+/// writes a checkpoint to `path` every `every` records
+pub struct Checkpointer {
+    path: PathBuf,
+    every: u64,
+    records: u64,
+    // skipped lines before resuming
+    skipped: u64,
+    rejects: Option<PathBuf>,
+}
+
+impl Checkpointer {
+    pub fn new(path: impl Into<PathBuf>, every: u64) -> Self {
+        Checkpointer { path: path.into(), every: every.max(1), records: 0, skipped: 0, rejects: None }
+    }
+
+    /// records the length of the rejects report written to `path`, to truncate it back to on resume
+    pub fn with_rejects(mut self, path: impl Into<PathBuf>) -> Self {
+        self.rejects = Some(path.into());
+        self
+    }
+
+    /// the last checkpoint, if any; later checkpoints carry its skipped lines on
+    pub fn resume(&mut self) -> Result<Option<Checkpoint>, Error> {
+        let file = match File::open(&self.path) {
+            Ok(file) => { file }
+            Err(error) if error.kind() == ErrorKind::NotFound => { return Ok(None); }
+            Err(error) => { return Err(error.into()); }
+        };
+        let mut reader = BufReader::new(file);
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let header: Header = serde_json::from_str(&line).map_err(SnapshotError::from)?;
+        if header.version != VERSION { return Err(SnapshotError::UnsupportedVersion(header.version).into()); }
+
+        let mut position = Position::new();
+        position.set_byte(header.byte).set_line(header.line).set_record(header.record);
+        self.skipped = header.skipped;
+        Ok(Some(Checkpoint { position, skipped: header.skipped, ledger: Ledger::restore(reader)?, rejects: header.rejects }))
+    }
+
+    /// call before applying the record at `position`, with the lines skipped so far and the rejects report written
+    /// to the path given to `with_rejects`; writes a checkpoint when due
+    pub fn tick<S: TransactionStore, E: io::Write>(&mut self, position: &Position, skipped: u64, ledger: &Ledger<S>, rejects: Option<&mut Writer<E>>) -> Result<(), Error> {
+        self.records += 1;
+        if self.records % self.every != 0 { return Ok(()); }
+
+        if let Some(rejects) = rejects { rejects.flush()?; }
+        let rejects = self.rejects.as_ref().map(fs::metadata).transpose()?.map_or(0, |metadata| metadata.len());
+        let header = Header { version: VERSION, byte: position.byte(), line: position.line(), record: position.record(), skipped: self.skipped + skipped, rejects };
+        // write-then-rename, so a crash can't leave a half-written checkpoint behind
+        let temp_path = format!("{}.tmp", self.path.display());
+        let mut writer = BufWriter::new(File::create(&temp_path)?);
+        serde_json::to_writer(&mut writer, &header).map_err(SnapshotError::from)?;
+        writer.write_all(b"\n")?;
+        ledger.snapshot(&mut writer)?;
+        writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&temp_path, &self.path)?;
+        Ok(())
+    }
+
+    /// removes the checkpoint once the run is complete
+    pub fn finish(self) -> Result<(), Error> {
+        match fs::remove_file(&self.path) {
+            Err(error) if error.kind() != ErrorKind::NotFound => { Err(error.into()) }
+            _ => { Ok(()) }
+        }
+    }
+}
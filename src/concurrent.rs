@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::ledger::{Client, Ledger, LedgerConfig, Transaction, TransactionError, TransactionKind};
+use crate::ledger::TransactionError::{DuplicateTransaction, ReferencedTransactionClientMismatch, ReferencedTransactionNonexistent};
+
+/// thread-safe `Ledger`, sharded by client id, so transactions of unrelated clients can be applied in parallel.
+/// Transactions of the same client must still be fed in order, e.g. by routing each client to a single thread.
+#[derive(Debug)]
+pub struct ConcurrentLedger {
+    shards: Vec<Mutex<Ledger>>,
+    // owning client of every stored transaction id, sharded by id; keeps ids unique across shards
+    owners: Vec<Mutex<HashMap<u64, u16>>>,
+}
+
+// transaction ids of all clients hash into the same owner shards, so use more of them to keep contention low
+const OWNER_SHARDS_PER_SHARD: usize = 4;
+
+impl ConcurrentLedger {
+    pub fn new(shard_count: usize) -> Self {
+        let shard_count = shard_count.max(1);
+        ConcurrentLedger {
+            shards: (0..shard_count).map(|_| Mutex::new(Ledger::new())).collect(),
+            owners: (0..shard_count * OWNER_SHARDS_PER_SHARD).map(|_| Mutex::new(HashMap::new())).collect(),
+        }
+    }
+
+    /// applies `config` to every shard, see `Ledger::with_config`; a dispute window would be counted by each shard on its own
+    pub fn with_config(mut self, config: LedgerConfig) -> Self {
+        self.shards = self.shards.into_iter().map(|shard| Mutex::new(shard.into_inner().expect("poisoned lock").with_config(config.clone()))).collect();
+        self
+    }
+
+    pub fn shard_count(&self) -> usize { self.shards.len() }
+
+    /// clients of the same shard are serialized, clients of different shards are processed in parallel
+    pub fn shard_of(&self, client_id: u16) -> usize { client_id as usize % self.shards.len() }
+
+    /// same as `Ledger::mutate`, but returns a copy of the client
+    pub fn mutate(&self, transaction: Transaction) -> Result<Client, TransactionError> {
+        // lock order is always owners, then ledger shard
+        let mut owners = self.owners[(transaction.id() % self.owners.len() as u64) as usize].lock().expect("poisoned lock");
+        let owner = owners.get(&transaction.id()).copied();
+        match (transaction.kind(), owner) {
+            (TransactionKind::Deposit | TransactionKind::Withdrawal, Some(owner)) if owner != transaction.client_id() => {
+                return Err(DuplicateTransaction);
+            }
+            (TransactionKind::Dispute | TransactionKind::Resolve | TransactionKind::Chargeback, None) => {
+                return Err(ReferencedTransactionNonexistent);
+            }
+            (TransactionKind::Dispute | TransactionKind::Resolve | TransactionKind::Chargeback, Some(owner)) if owner != transaction.client_id() => {
+                return Err(ReferencedTransactionClientMismatch);
+            }
+            _ => {}
+        }
+
+        let mut shard = self.shards[self.shard_of(transaction.client_id())].lock().expect("poisoned lock");
+        let client = shard.mutate(transaction)?.clone();
+        if let TransactionKind::Deposit | TransactionKind::Withdrawal = transaction.kind() {
+            owners.insert(transaction.id(), transaction.client_id());
+        }
+        Ok(client)
+    }
+
+    /// merges all shards into a single `Ledger`
+    pub fn into_ledger(self) -> Ledger {
+        Ledger::merge(self.shards.into_iter().map(|shard| shard.into_inner().expect("poisoned lock")).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::thread;
+
+    use crate::concurrent::ConcurrentLedger;
+    use crate::ledger::{Ledger, LedgerConfig, Transaction, TransactionError, TransactionKind, WithdrawalDisputes};
+    use crate::money::Currency;
+
+    // deterministic input with all kinds of transactions, including disputes referring to other clients' transactions
+    fn generate(count: u64, clients: u64) -> Vec<Transaction> {
+        let mut seed: u64 = 42;
+        let mut next = move |bound: u64| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (seed >> 33) % bound
+        };
+        let mut last_ids: HashMap<u16, u64> = HashMap::new();
+        (0..count).map(|id| {
+            let client_id = next(clients) as u16;
+            let amount = format!("{}.{}", next(100), next(100)).parse().unwrap();
+            // mostly refer to the client's own last deposit, sometimes to a random one
+            let reference = match (next(4), last_ids.get(&client_id)) {
+                (0, _) | (_, None) => { next(id + 1) }
+                (_, Some(&last)) => { last }
+            };
+            let kind = match next(40) {
+                0..=19 => { TransactionKind::Deposit }
+                20..=29 => { TransactionKind::Withdrawal }
+                30..=34 => { TransactionKind::Dispute }
+                35..=38 => { TransactionKind::Resolve }
+                _ => { TransactionKind::Chargeback }
+            };
+            let id = match kind {
+                TransactionKind::Deposit => { last_ids.insert(client_id, id); id }
+                TransactionKind::Withdrawal => { id }
+                _ => { reference }
+            };
+            Transaction::new(id, client_id, kind, amount, Currency::default())
+        }).collect()
+    }
+
+    fn sequential(transactions: &[Transaction]) -> Ledger {
+        let mut ledger = Ledger::new();
+        for transaction in transactions {
+            let _ = ledger.mutate(*transaction);
+        }
+        ledger
+    }
+
+    fn concurrent(transactions: &[Transaction], threads: usize, shards: usize) -> Ledger {
+        let ledger = ConcurrentLedger::new(shards);
+        thread::scope(|scope| {
+            for thread in 0..threads {
+                let ledger = &ledger;
+                scope.spawn(move || {
+                    // every client is handled by a single thread, in input order
+                    for transaction in transactions.iter().filter(|t| t.client_id() as usize % threads == thread) {
+                        let _ = ledger.mutate(*transaction);
+                    }
+                });
+            }
+        });
+        ledger.into_ledger()
+    }
+
+    #[test]
+    fn matches_sequential() {
+        let transactions = generate(50_000, 100);
+        let expected = sequential(&transactions);
+        assert!(expected.values().any(|c| c.locked()));
+        assert!(expected.values().any(|c| !c.locked()));
+        for (threads, shards) in [(1, 1), (4, 4), (8, 3), (16, 32)] {
+            assert_eq!(*concurrent(&transactions, threads, shards), *expected);
+        }
+    }
+
+    #[test]
+    fn stress() {
+        let transactions = generate(100_000, 1000);
+        let expected = sequential(&transactions);
+        for _ in 0..3 {
+            assert_eq!(*concurrent(&transactions, 8, 8), *expected);
+        }
+    }
+
+    #[test]
+    fn duplicate_across_shards() {
+        let ledger = ConcurrentLedger::new(2);
+        ledger.mutate(Transaction::new(1, 0, TransactionKind::Deposit, "10".parse().unwrap(), Currency::default())).expect("");
+        ledger.mutate(Transaction::new(1, 1, TransactionKind::Deposit, "10".parse().unwrap(), Currency::default())).expect_err("");
+        ledger.mutate(Transaction::new(1, 1, TransactionKind::Dispute, "0".parse().unwrap(), Currency::default())).expect_err("");
+        let ledger = ledger.into_ledger();
+        assert_eq!(ledger.len(), 1);
+        assert_eq!(ledger[&0].balance(Currency::default()).available(), "10".parse().unwrap());
+    }
+
+    #[test]
+    fn config() {
+        let ledger = ConcurrentLedger::new(2).with_config(LedgerConfig { withdrawal_disputes: WithdrawalDisputes::Refuse, ..Default::default() });
+        for client_id in 0..2 {
+            let transaction = |id, kind, amount: &str| Transaction::new(id, client_id, kind, amount.parse().unwrap(), Currency::default());
+            ledger.mutate(transaction(client_id as u64 * 2, TransactionKind::Deposit, "10")).expect("");
+            ledger.mutate(transaction(client_id as u64 * 2 + 1, TransactionKind::Withdrawal, "4")).expect("");
+            assert_eq!(ledger.mutate(transaction(client_id as u64 * 2 + 1, TransactionKind::Dispute, "0")).unwrap_err(), TransactionError::WithdrawalNotDisputable);
+        }
+    }
+}
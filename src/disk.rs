@@ -0,0 +1,386 @@
+//! `TransactionStore` spilling to disk, for histories that don't fit in memory
+//!
+//! A small log-structured merge tree: recent transactions, and state changes of older ones, are kept in memory;
+//! once there are enough of them, they are written to a sorted run file. Lookups go from the newest run to the oldest,
+//! reading a single block of each run thanks to a sparse in-memory index; runs of similar size are merged into a bigger one.
+//! Removals are written as tombstones, shadowing older copies until merged into the oldest run.
+
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::iter::Peekable;
+use std::path::{Path, PathBuf};
+use std::iter;
+
+use crate::amount::Amount;
+use crate::ledger::{Transaction, TransactionState};
+use crate::store::{CompactStore, pack, REMOVED, StoredTransaction, TransactionStore, unpack};
+
+// `id: u64, client: u16, kind and state: u8, currency: [u8; 3], amount: i64`, little endian; a tombstone only has the id and `REMOVED`
+const RECORD_LEN: usize = 22;
+// records per block; the index keeps the first id of every block
+const BLOCK_LEN: usize = 256;
+// runs of a tier merged at once into one of the next tier, `FANOUT` times bigger
+const FANOUT: usize = 4;
+
+/// a transaction of a run, `None` if removed
+type Entry = (u64, Option<StoredTransaction>);
+
+#[derive(Debug)]
+struct Run {
+    path: PathBuf,
+    file: RefCell<File>,
+    len: usize,
+    last: u64,
+    index: Vec<u64>,
+}
+
+/// Writes flush a full memtable before changing anything, so a failed one leaves the store as it was; still, after an I/O error,
+/// runs may be gone with part of the history, so the ledger can't go on: recover with the journal instead.
+/// Run files live in a directory of their own and are removed on drop, so this is a spill area rather than a database
+#[derive(Debug)]
+pub struct DiskStore {
+    dir: PathBuf,
+    memtable: CompactStore,
+    // removed since the last run, possibly still in older runs
+    removed: HashSet<u64>,
+    memtable_limit: usize,
+    // oldest first
+    runs: Vec<Run>,
+    next_run: u64,
+}
+
+impl DiskStore {
+    /// stores runs in `dir`, writing one once `memtable_limit` transactions or removals are kept in memory, before the next one;
+    /// runs left behind by a crashed run are removed
+    pub fn new(dir: impl Into<PathBuf>, memtable_limit: usize) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+            if name.starts_with("run-") && name.ends_with(".dat") { fs::remove_file(&path)?; }
+        }
+        Ok(DiskStore { dir, memtable: CompactStore::new(), removed: HashSet::new(), memtable_limit: memtable_limit.max(1), runs: Vec::new(), next_run: 0 })
+    }
+
+    /// number of run files
+    pub fn runs(&self) -> usize { self.runs.len() }
+
+    fn make_room(&mut self) -> io::Result<()> {
+        if self.memtable.len() + self.removed.len() >= self.memtable_limit { self.flush()?; }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let path = self.next_path();
+        let run = write_run(path, self.memory_entries().into_iter().map(Ok))?;
+        self.runs.extend(run);
+        self.memtable = CompactStore::new();
+        self.removed.clear();
+
+        // size-tiered: runs get older and bigger, so once the newest `FANOUT` share a tier they are merged,
+        // and every transaction is rewritten about once per tier
+        while self.runs.len() >= FANOUT {
+            let start = self.runs.len() - FANOUT;
+            let tier = self.tier(&self.runs[start]);
+            if self.runs[start..].iter().any(|run| self.tier(run) != tier) { break; }
+            let path = self.next_path();
+            // once merged into the oldest run, there's nothing older left for tombstones to shadow
+            let merged = Merge::new(self.runs[start..].iter().map(Run::iter).collect()).filter(|entry| start > 0 || !matches!(entry, Ok((_, None))));
+            let merged = write_run(path, merged)?;
+            let old = self.runs.split_off(start);
+            self.runs.extend(merged);
+            for run in old {
+                fs::remove_file(&run.path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 0 for runs of about one memtable, 1 for `FANOUT` memtables and so on
+    fn tier(&self, run: &Run) -> u32 { (run.len / self.memtable_limit).max(1).ilog(FANOUT) }
+
+    fn next_path(&mut self) -> PathBuf {
+        self.next_run += 1;
+        self.dir.join(format!("run-{:08}.dat", self.next_run - 1))
+    }
+
+    /// memtable and tombstones, sorted by id
+    fn memory_entries(&self) -> Vec<Entry> {
+        let mut entries: Vec<Entry> = self.memtable.iter().map(|t| (t.transaction().id(), Some(t)))
+            .chain(self.removed.iter().map(|&id| (id, None)))
+            .collect();
+        entries.sort_by_key(|(id, _)| *id);
+        entries
+    }
+
+}
+
+/// writes `entries`, sorted by id, to a run at `path`; `None` if it's empty. A partly written run is removed
+fn write_run(path: PathBuf, entries: impl Iterator<Item=io::Result<Entry>>) -> io::Result<Option<Run>> {
+    let write = |path: &Path| -> io::Result<(usize, u64, Vec<u64>)> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        let (mut len, mut last, mut index) = (0usize, 0, Vec::new());
+        for entry in entries {
+            let (id, stored) = entry?;
+            if len % BLOCK_LEN == 0 { index.push(id); }
+            writer.write_all(&encode(id, stored.as_ref()))?;
+            len += 1;
+            last = id;
+        }
+        writer.flush()?;
+        Ok((len, last, index))
+    };
+    let (len, last, index) = write(&path).map_err(|err| { let _ = fs::remove_file(&path); err })?;
+    if len == 0 {
+        fs::remove_file(&path)?;
+        return Ok(None);
+    }
+    Ok(Some(Run { file: RefCell::new(File::open(&path)?), path, len, last, index }))
+}
+
+impl Drop for DiskStore {
+    fn drop(&mut self) {
+        for run in &self.runs {
+            let _ = fs::remove_file(&run.path);
+        }
+    }
+}
+
+impl TransactionStore for DiskStore {
+    fn get(&self, id: u64) -> io::Result<Option<StoredTransaction>> {
+        if let Some(stored) = self.memtable.get(id) { return Ok(Some(stored)); }
+        if self.removed.contains(&id) { return Ok(None); }
+        for run in self.runs.iter().rev() {
+            if let Some(stored) = run.get(id)? { return Ok(stored); }
+        }
+        Ok(None)
+    }
+
+    fn insert(&mut self, stored: StoredTransaction) -> io::Result<()> {
+        self.make_room()?;
+        self.removed.remove(&stored.transaction().id());
+        self.memtable.insert(stored);
+        Ok(())
+    }
+
+    fn set_state(&mut self, id: u64, state: TransactionState) -> io::Result<bool> {
+        if self.memtable.set_state(id, state) { return Ok(true); }
+        // newer entries shadow older ones, so an updated copy is as good as an update
+        let Some(stored) = self.get(id)? else { return Ok(false); };
+        self.insert(StoredTransaction::new(*stored.transaction(), state))?;
+        Ok(true)
+    }
+
+    fn remove(&mut self, id: u64) -> io::Result<()> {
+        self.make_room()?;
+        self.memtable.remove(id);
+        if self.runs.is_empty() { return Ok(()); }
+        self.removed.insert(id);
+        Ok(())
+    }
+
+    /// reads all runs, merging them on the fly
+    fn iter(&self) -> Box<dyn Iterator<Item=io::Result<StoredTransaction>> + '_> {
+        let mut sources: Vec<Box<dyn Iterator<Item=io::Result<Entry>>>> = self.runs.iter().map(Run::iter).collect();
+        sources.push(Box::new(self.memory_entries().into_iter().map(Ok)));
+        Box::new(Merge::new(sources).filter_map(|entry| entry.map(|(_, stored)| stored).transpose()))
+    }
+}
+
+impl Run {
+    /// `Some(None)` if there's a tombstone for `id`
+    fn get(&self, id: u64) -> io::Result<Option<Option<StoredTransaction>>> {
+        let block = self.index.partition_point(|&first| first <= id);
+        if block == 0 || id > self.last { return Ok(None); }
+        let start = (block - 1) * BLOCK_LEN;
+        let count = BLOCK_LEN.min(self.len - start);
+
+        let mut bytes = vec![0u8; count * RECORD_LEN];
+        let mut file = self.file.borrow_mut();
+        file.seek(SeekFrom::Start((start * RECORD_LEN) as u64))?;
+        file.read_exact(&mut bytes)?;
+        let records: Vec<&[u8]> = bytes.chunks(RECORD_LEN).collect();
+        match records.binary_search_by_key(&id, |record| u64::from_le_bytes(record[0..8].try_into().unwrap())) {
+            Ok(index) => { decode(records[index]).map(|(_, stored)| Some(stored)) }
+            Err(_) => { Ok(None) }
+        }
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item=io::Result<Entry>>> {
+        let mut reader = match File::open(&self.path) {
+            Ok(file) => { BufReader::new(file) }
+            Err(error) => { return Box::new(iter::once(Err(error))); }
+        };
+        Box::new((0..self.len).map(move |_| {
+            let mut record = [0u8; RECORD_LEN];
+            reader.read_exact(&mut record)?;
+            decode(&record)
+        }))
+    }
+}
+
+/// merges sorted sources, given oldest first; of entries with the same id, the newest one wins. Errors come first
+struct Merge<'a> {
+    sources: Vec<Peekable<Box<dyn Iterator<Item=io::Result<Entry>> + 'a>>>,
+}
+
+impl<'a> Merge<'a> {
+    fn new(sources: Vec<Box<dyn Iterator<Item=io::Result<Entry>> + 'a>>) -> Self {
+        Merge { sources: sources.into_iter().map(Iterator::peekable).collect() }
+    }
+}
+
+impl Iterator for Merge<'_> {
+    type Item = io::Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for source in self.sources.iter_mut() {
+            if let Some(Err(_)) = source.peek() { return source.next(); }
+        }
+        let id = self.sources.iter_mut().filter_map(|source| source.peek().and_then(|entry| entry.as_ref().ok()).map(|(id, _)| *id)).min()?;
+        self.sources.iter_mut().filter_map(|source| source.next_if(|entry| matches!(entry, Ok((next, _)) if *next == id))).last()
+    }
+}
+
+fn encode(id: u64, stored: Option<&StoredTransaction>) -> [u8; RECORD_LEN] {
+    let mut record = [0u8; RECORD_LEN];
+    record[0..8].copy_from_slice(&id.to_le_bytes());
+    let Some(stored) = stored else {
+        record[10] = REMOVED;
+        return record;
+    };
+    let transaction = stored.transaction();
+    record[8..10].copy_from_slice(&transaction.client_id().to_le_bytes());
+    record[10] = pack(transaction.kind(), stored.state());
+    record[11..14].copy_from_slice(transaction.currency().code().as_bytes());
+    record[14..22].copy_from_slice(&transaction.amount().fx4().to_le_bytes());
+    record
+}
+
+fn decode(record: &[u8]) -> io::Result<Entry> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid run record");
+    let id = u64::from_le_bytes(record[0..8].try_into().unwrap());
+    if record[10] == REMOVED { return Ok((id, None)); }
+    let client_id = u16::from_le_bytes(record[8..10].try_into().unwrap());
+    let (kind, state) = unpack(record[10]).ok_or_else(invalid)?;
+    let currency = std::str::from_utf8(&record[11..14]).ok().and_then(|code| code.parse().ok()).ok_or_else(invalid)?;
+    let amount = Amount::from_fx4(i64::from_le_bytes(record[14..22].try_into().unwrap()));
+    Ok((id, Some(StoredTransaction::new(Transaction::new(id, client_id, kind, amount, currency), state))))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, io, process};
+
+    use crate::disk::{DiskStore, FANOUT};
+    use crate::ledger::{Ledger, Transaction, TransactionError, TransactionKind, TransactionState};
+    use crate::store::{CompactStore, StoredTransaction, TransactionStore};
+
+    fn stored(id: u64, state: TransactionState) -> StoredTransaction {
+        let kind = if id % 3 == 0 { TransactionKind::Withdrawal } else { TransactionKind::Deposit };
+        StoredTransaction::new(Transaction::new(id, (id % 11) as u16, kind, "1.5".parse().unwrap(), "EUR".parse().unwrap()), state)
+    }
+
+    #[test]
+    fn matches_memory() {
+        let dir = env::temp_dir().join(format!("disk-{}-matches_memory", process::id()));
+        let mut disk = DiskStore::new(&dir, 100).unwrap();
+        let mut memory = CompactStore::new();
+
+        // mostly increasing ids, with some stragglers, plus state changes and removals of old transactions
+        let mut seed: u64 = 42;
+        for i in 0..20_000u64 {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            let id = if (seed >> 33) % 10 == 0 { (seed >> 20) % (i + 1) + 1_000_000 } else { i };
+            let transaction = stored(id, TransactionState::Processed);
+            disk.insert(transaction).unwrap();
+            memory.insert(transaction);
+            if (seed >> 33) % 7 == 0 {
+                let old = (seed >> 13) % (i + 1);
+                assert_eq!(disk.set_state(old, TransactionState::Disputed).unwrap(), memory.set_state(old, TransactionState::Disputed));
+            }
+            if (seed >> 33) % 13 == 0 {
+                let old = (seed >> 7) % (i + 1);
+                disk.remove(old).unwrap();
+                memory.remove(old);
+            }
+        }
+        assert!(disk.runs() > 0 && disk.runs() < 4 * (FANOUT - 1));
+
+        for id in (0..20_000).chain(1_000_000..1_020_000) {
+            assert_eq!(TransactionStore::get(&disk, id).unwrap(), memory.get(id));
+        }
+        let mut expected: Vec<StoredTransaction> = memory.iter().collect();
+        expected.sort_by_key(|t| t.transaction().id());
+        assert_eq!(TransactionStore::iter(&disk).collect::<io::Result<Vec<_>>>().unwrap(), expected);
+
+        drop(disk);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn compaction() {
+        let dir = env::temp_dir().join(format!("disk-{}-compaction", process::id()));
+        let mut disk = DiskStore::new(&dir, 1).unwrap();
+        // without removals, runs count in base `FANOUT`: 100 is 1210 in base 4, plus the one in memory
+        for id in 0..101 {
+            disk.insert(stored(id, TransactionState::Processed)).unwrap();
+        }
+        assert_eq!(disk.runs.iter().map(|run| run.len).collect::<Vec<_>>(), vec![64, 16, 16, 4]);
+        drop(disk);
+        fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn ledger() {
+        let dir = env::temp_dir().join(format!("disk-{}-ledger", process::id()));
+        let mut disk = Ledger::with_store(DiskStore::new(&dir, 2).unwrap());
+        let mut memory = Ledger::new();
+        let transactions = [
+            Transaction::new(1, 1, TransactionKind::Deposit, "10".parse().unwrap(), Default::default()),
+            Transaction::new(2, 1, TransactionKind::Deposit, "5".parse().unwrap(), Default::default()),
+            Transaction::new(3, 2, TransactionKind::Deposit, "5".parse().unwrap(), Default::default()),
+            Transaction::new(4, 2, TransactionKind::Withdrawal, "1".parse().unwrap(), Default::default()),
+            Transaction::new(1, 1, TransactionKind::Dispute, "0".parse().unwrap(), Default::default()),
+            Transaction::new(1, 1, TransactionKind::Dispute, "0".parse().unwrap(), Default::default()),
+            Transaction::new(3, 1, TransactionKind::Dispute, "0".parse().unwrap(), Default::default()),
+            Transaction::new(2, 1, TransactionKind::Deposit, "6".parse().unwrap(), Default::default()),
+            Transaction::new(1, 1, TransactionKind::Chargeback, "0".parse().unwrap(), Default::default()),
+        ];
+        for transaction in transactions {
+            assert_eq!(disk.mutate(transaction).cloned(), memory.mutate(transaction).cloned());
+        }
+        assert_eq!(*disk, *memory);
+        drop(disk);
+        fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn io_errors() {
+        let dir = env::temp_dir().join(format!("disk-{}-io_errors", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("run-00000003.dat"), b"left behind").unwrap();
+        fs::write(dir.join("notes.txt"), b"not a run").unwrap();
+        let mut disk = Ledger::with_store(DiskStore::new(&dir, 2).unwrap());
+        assert!(!dir.join("run-00000003.dat").exists() && dir.join("notes.txt").exists());
+
+        let deposit = |id| Transaction::new(id, 1, TransactionKind::Deposit, "1".parse().unwrap(), Default::default());
+        for id in 1..4 {
+            disk.mutate(deposit(id)).unwrap();
+        }
+        // the run is gone, and so is the directory to write the next one to
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(disk.snapshot(io::sink()).is_err());
+        disk.mutate(deposit(4)).unwrap();
+        assert_eq!(disk.mutate(deposit(5)).unwrap_err(), TransactionError::Store(io::ErrorKind::NotFound));
+        // and nothing of it was applied
+        assert_eq!(disk[&1].balance(Default::default()).available(), "4".parse().unwrap());
+        assert_eq!(disk.mutate(Transaction::new(5, 1, TransactionKind::Dispute, "0".parse().unwrap(), Default::default())).unwrap_err(), TransactionError::ReferencedTransactionNonexistent);
+    }
+}
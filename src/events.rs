@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+use std::ops::Deref;
+
+use crate::ledger::{Client, Ledger, LedgerConfig, Retention, Transaction, TransactionError};
+
+/// a transaction fed to an `EventSourcedLedger`, with its outcome
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Event {
+    seq: u64,
+    transaction: Transaction,
+    error: Option<TransactionError>,
+}
+
+impl Event {
+    /// position in the event store, starting from 0
+    pub fn seq(&self) -> u64 { self.seq }
+    pub fn transaction(&self) -> &Transaction { &self.transaction }
+    /// reason of refusal, `None` if the transaction was accepted
+    pub fn error(&self) -> Option<TransactionError> { self.error }
+    pub fn accepted(&self) -> bool { self.error.is_none() }
+}
+
+/// append-only log of every event, indexed by client and by transaction id
+#[derive(Debug, Default)]
+pub struct EventStore {
+    events: Vec<Event>,
+    by_client: HashMap<u16, Vec<u64>>,
+    by_tx: HashMap<u64, Vec<u64>>,
+}
+
+impl EventStore {
+    pub fn new() -> Self {
+        EventStore { ..Default::default() }
+    }
+
+    pub fn append(&mut self, transaction: Transaction, error: Option<TransactionError>) -> &Event {
+        let seq = self.events.len() as u64;
+        self.by_client.entry(transaction.client_id()).or_default().push(seq);
+        self.by_tx.entry(transaction.id()).or_default().push(seq);
+        self.events.push(Event { seq, transaction, error });
+        &self.events[seq as usize]
+    }
+
+    pub fn events(&self) -> &[Event] { &self.events }
+
+    /// every event of `client_id`, in order
+    pub fn history(&self, client_id: u16) -> impl Iterator<Item=&Event> {
+        self.by_client.get(&client_id).into_iter().flatten().map(|&seq| &self.events[seq as usize])
+    }
+
+    /// every event with `tx_id`: the deposit or withdrawal itself, plus its disputes, resolves and chargebacks, in order
+    pub fn events_for_tx(&self, tx_id: u64) -> impl Iterator<Item=&Event> {
+        self.by_tx.get(&tx_id).into_iter().flatten().map(|&seq| &self.events[seq as usize])
+    }
+}
+
+/// `Ledger` recording every transaction it's fed, accepted or refused, in an `EventStore`; dereferences to the ledger.
+/// Memory grows with every event, so it's meant for investigations rather than bulk processing
+#[derive(Debug, Default)]
+pub struct EventSourcedLedger {
+    ledger: Ledger,
+    events: EventStore,
+}
+
+impl Deref for EventSourcedLedger {
+    type Target = Ledger;
+
+    fn deref(&self) -> &Self::Target {
+        &self.ledger
+    }
+}
+
+impl EventSourcedLedger {
+    pub fn new() -> Self {
+        EventSourcedLedger { ..Default::default() }
+    }
+
+    /// see `Ledger::with_config`; `balance_at` replays with it, too
+    pub fn with_config(mut self, config: LedgerConfig) -> Self {
+        self.ledger = self.ledger.with_config(config);
+        self
+    }
+
+    /// same as `Ledger::mutate`, but records the event
+    pub fn mutate(&mut self, transaction: Transaction) -> Result<&Client, TransactionError> {
+        match self.ledger.mutate(transaction) {
+            Ok(_) => {
+                self.events.append(transaction, None);
+                Ok(&self.ledger[&transaction.client_id()])
+            }
+            Err(error) => {
+                self.events.append(transaction, Some(error));
+                Err(error)
+            }
+        }
+    }
+
+    pub fn events(&self) -> &EventStore { &self.events }
+
+    /// every event of `client_id`, in order
+    pub fn history(&self, client_id: u16) -> impl Iterator<Item=&Event> { self.events.history(client_id) }
+
+    /// every event referring to `tx_id`, in order
+    pub fn events_for_tx(&self, tx_id: u64) -> impl Iterator<Item=&Event> { self.events.events_for_tx(tx_id) }
+
+    /// state of `client_id` right after event `seq`, `None` if it had no accepted transaction by then.
+    /// Rebuilt by replaying the client's accepted events with the ledger's config: clients can only refer to their own transactions,
+    /// so that's all it depends on. The dispute window is left out, as it counts the events of all clients, and accepted events only
+    /// refer to transactions still in it anyway. Fails only if the replay does, e.g. with a `LedgerPolicy` that isn't deterministic
+    pub fn balance_at(&self, client_id: u16, seq: u64) -> Result<Option<Client>, TransactionError> {
+        let mut ledger = Ledger::new().with_config(LedgerConfig { retention: Retention::Forever, ..self.ledger.config().clone() });
+        for event in self.history(client_id).take_while(|e| e.seq <= seq).filter(|e| e.accepted()) {
+            ledger.mutate(event.transaction)?;
+        }
+        Ok(ledger.get(&client_id).cloned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::events::EventSourcedLedger;
+    use crate::ledger::{LedgerConfig, Transaction, TransactionError, TransactionKind};
+    use crate::policy::Overdraft;
+    use crate::Amount;
+
+    fn transaction(id: u64, client_id: u16, kind: TransactionKind, amount: &str) -> Transaction {
+        Transaction::new(id, client_id, kind, amount.parse().unwrap(), Default::default())
+    }
+
+    fn ledger() -> EventSourcedLedger {
+        let mut ledger = EventSourcedLedger::new();
+        ledger.mutate(transaction(1, 1, TransactionKind::Deposit, "10")).unwrap();
+        ledger.mutate(transaction(2, 2, TransactionKind::Deposit, "5")).unwrap();
+        ledger.mutate(transaction(3, 1, TransactionKind::Withdrawal, "20")).unwrap_err();
+        ledger.mutate(transaction(1, 1, TransactionKind::Dispute, "0")).unwrap();
+        ledger.mutate(transaction(1, 2, TransactionKind::Resolve, "0")).unwrap_err();
+        ledger.mutate(transaction(1, 1, TransactionKind::Chargeback, "0")).unwrap();
+        ledger.mutate(transaction(4, 1, TransactionKind::Deposit, "1")).unwrap_err();
+        ledger
+    }
+
+    #[test]
+    fn history() {
+        let ledger = ledger();
+        assert_eq!(ledger.events().events().len(), 7);
+        assert_eq!(ledger.history(1).map(|e| e.seq()).collect::<Vec<_>>(), vec![0, 2, 3, 5, 6]);
+        assert_eq!(ledger.history(1).map(|e| e.error()).collect::<Vec<_>>(),
+                   vec![None, Some(TransactionError::NegativeBalance), None, None, Some(TransactionError::ClientLocked)]);
+        assert_eq!(ledger.history(3).count(), 0);
+    }
+
+    #[test]
+    fn events_for_tx() {
+        let ledger = ledger();
+        let kinds: Vec<(TransactionKind, bool)> = ledger.events_for_tx(1).map(|e| (e.transaction().kind(), e.accepted())).collect();
+        assert_eq!(kinds, vec![
+            (TransactionKind::Deposit, true), (TransactionKind::Dispute, true), (TransactionKind::Resolve, false), (TransactionKind::Chargeback, true),
+        ]);
+    }
+
+    #[test]
+    fn balance_at() {
+        let ledger = ledger();
+        let held = |seq| ledger.balance_at(1, seq).unwrap().map(|c| c.balance(Default::default()).held());
+        assert_eq!(held(0), Some("0".parse::<Amount>().unwrap()));
+        // right before the chargeback
+        assert_eq!(held(4), Some("10".parse().unwrap()));
+        assert_eq!(held(5), Some("0".parse().unwrap()));
+        assert!(!ledger.balance_at(1, 4).unwrap().unwrap().locked());
+        assert!(ledger.balance_at(1, 6).unwrap().unwrap().locked());
+        assert_eq!(ledger.balance_at(1, 6).unwrap().as_ref(), ledger.get(&1));
+        assert_eq!(ledger.balance_at(2, 0), Ok(None));
+
+        // an overdraft only the ledger's policy allows
+        let mut ledger = EventSourcedLedger::new().with_config(LedgerConfig { policy: Arc::new(Overdraft::new("10".parse().unwrap())), ..Default::default() });
+        ledger.mutate(transaction(1, 1, TransactionKind::Deposit, "10")).unwrap();
+        ledger.mutate(transaction(2, 1, TransactionKind::Withdrawal, "15")).unwrap();
+        assert_eq!(ledger.balance_at(1, 1).unwrap().unwrap().balance(Default::default()).available(), "-5".parse().unwrap());
+    }
+}
@@ -0,0 +1,343 @@
+//! Write-ahead journal of accepted and refused transactions, to rebuild a `Ledger` and its rejects report after a crash
+//!
+//! Binary, append-only file: a header (magic + version), then fixed size records of
+//! `line: u64, id: u64, client: u16, kind: u8, currency: [u8; 3], amount: i64, crc32: u32`, little endian.
+//! A refused transaction only keeps its line, with kind `REFUSED` and the error as the first byte of `id`; the input has the rest.
+//! A crash can leave the last record torn; that one is dropped on open, anything else failing the checksum is corruption.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::amount::Amount;
+use crate::ledger::{Ledger, LockMode, Transaction, TransactionError, TransactionKind};
+use crate::store::TransactionStore;
+use crate::TransactionKind::{Chargeback, Close, Deposit, Dispute, Freeze, Resolve, Unlock, Withdrawal};
+
+const MAGIC: &[u8; 4] = b"RCTJ";
+const VERSION: u32 = 1;
+const HEADER_LEN: u64 = 8;
+const RECORD_LEN: u64 = 34;
+// index is the on-disk value of the kind
+const KINDS: [TransactionKind; 11] = [
+    Deposit, Withdrawal, Dispute, Resolve, Chargeback, Unlock, Freeze(None), Close,
+    Freeze(Some(LockMode::Debits)), Freeze(Some(LockMode::Everything)), Freeze(Some(LockMode::DisputesOnly)),
+];
+const REFUSED: u8 = 0xFF;
+// index is the on-disk value of the error; `Store` errors fail the run, so they are never journaled
+const ERRORS: [TransactionError; 19] = [
+    TransactionError::NegativeBalance, TransactionError::NegativeTransaction, TransactionError::ClientLocked, TransactionError::ClientFrozen,
+    TransactionError::ClientClosed, TransactionError::ClientNonexistent, TransactionError::InvalidStatusChange, TransactionError::ClientNotSettled,
+    TransactionError::ReferencedTransactionNonexistent, TransactionError::ReferencedTransactionClientMismatch, TransactionError::AlreadyDisputed,
+    TransactionError::NotDisputed, TransactionError::DisputeAlreadySettled, TransactionError::DuplicateTransaction, TransactionError::DisputeWindowExpired,
+    TransactionError::WithdrawalNotDisputable, TransactionError::InvalidDisputeAmount, TransactionError::Overflow, TransactionError::EvictedTransactionId,
+];
+
+/// when appended records are forced to disk; records not yet synced are lost on a power failure, but not on a process crash
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum FsyncPolicy {
+    /// after every record: safest, and slowest by far
+    Always,
+    /// after every n records
+    Every(u32),
+    /// only when the journal is closed, leave the rest to the OS
+    Never,
+}
+
+impl Default for FsyncPolicy {
+    fn default() -> Self { FsyncPolicy::Every(1000) }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// not a journal, or a record in the middle of it is broken; with the byte offset
+    Corrupted(u64),
+    UnsupportedVersion(u32),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => { write!(f, "journal I/O error: {}", e) }
+            Error::Corrupted(offset) => { write!(f, "journal corrupted at byte {}", offset) }
+            Error::UnsupportedVersion(version) => { write!(f, "unsupported journal version: {}", version) }
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+pub struct Journal {
+    path: PathBuf,
+    // unbuffered: every record reaches the OS as soon as it's appended
+    file: File,
+    fsync: FsyncPolicy,
+    unsynced: u32,
+    last_line: u64,
+    // reasons of the refused lines, to report them again
+    refusals: HashMap<u64, TransactionError>,
+}
+
+impl Journal {
+    /// opens the journal at `path`, creating it if needed, and replays its accepted transactions onto `ledger`;
+    /// a torn last record is truncated
+    pub fn open<S: TransactionStore>(path: impl AsRef<Path>, fsync: FsyncPolicy, ledger: &mut Ledger<S>) -> Result<Journal, Error> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)?;
+        let len = file.metadata()?.len();
+        let mut last_line = 0;
+        let mut refusals = HashMap::new();
+        let mut valid = 0;
+
+        if len >= HEADER_LEN {
+            let mut reader = BufReader::new(&file);
+            let mut header = [0u8; HEADER_LEN as usize];
+            reader.read_exact(&mut header)?;
+            if &header[..4] != MAGIC { return Err(Error::Corrupted(0)); }
+            let version = u32::from_le_bytes(header[4..].try_into().unwrap());
+            if version != VERSION { return Err(Error::UnsupportedVersion(version)); }
+            valid = HEADER_LEN;
+
+            let mut record = [0u8; RECORD_LEN as usize];
+            while valid + RECORD_LEN <= len {
+                reader.read_exact(&mut record)?;
+                match decode(&record) {
+                    Some((line, outcome)) => {
+                        match outcome {
+                            // accepted transactions must be accepted again
+                            Ok(transaction) => {
+                                match ledger.mutate(transaction) {
+                                    Err(TransactionError::Store(kind)) => { return Err(Error::Io(kind.into())); }
+                                    Err(_) => { return Err(Error::Corrupted(valid)); }
+                                    Ok(_) => {}
+                                }
+                            }
+                            Err(error) => { refusals.insert(line, error); }
+                        }
+                        last_line = line;
+                        valid += RECORD_LEN;
+                    }
+                    None if valid + RECORD_LEN == len => { break; }
+                    None => { return Err(Error::Corrupted(valid)); }
+                }
+            }
+        }
+
+        if valid < HEADER_LEN {
+            file.set_len(0)?;
+            file.write_all(MAGIC)?;
+            file.write_all(&VERSION.to_le_bytes())?;
+            file.sync_data()?;
+        } else if valid < len {
+            file.set_len(valid)?;
+            file.sync_data()?;
+        }
+        file.seek(SeekFrom::End(0))?;
+
+        Ok(Journal { path, file, fsync, unsynced: 0, last_line, refusals })
+    }
+
+    /// input line of the last journaled transaction, 0 if none
+    pub fn last_line(&self) -> u64 { self.last_line }
+
+    /// why the transaction at `line` was refused, `None` if it wasn't journaled as refused
+    pub fn refusal(&self, line: u64) -> Option<TransactionError> { self.refusals.get(&line).copied() }
+
+    /// records an accepted transaction, read from input `line`
+    pub fn append(&mut self, line: u64, transaction: &Transaction) -> Result<(), Error> {
+        self.write(line, Ok(transaction))
+    }
+
+    /// records a transaction refused with `error`, read from input `line`
+    pub fn refuse(&mut self, line: u64, error: TransactionError) -> Result<(), Error> {
+        self.write(line, Err(error))
+    }
+
+    fn write(&mut self, line: u64, outcome: Result<&Transaction, TransactionError>) -> Result<(), Error> {
+        self.file.write_all(&encode(line, outcome))?;
+        self.last_line = line;
+        self.unsynced += 1;
+        match self.fsync {
+            FsyncPolicy::Always => { self.sync() }
+            FsyncPolicy::Every(n) if self.unsynced >= n => { self.sync() }
+            _ => { Ok(()) }
+        }
+    }
+
+    /// forces all appended records to disk
+    pub fn sync(&mut self) -> Result<(), Error> {
+        self.file.sync_data()?;
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    /// removes the journal once the run is complete, so the next run starts over rather than replaying this one
+    pub fn finish(self) -> Result<(), Error> {
+        drop(self.file);
+        fs::remove_file(&self.path)?;
+        Ok(())
+    }
+}
+
+fn encode(line: u64, outcome: Result<&Transaction, TransactionError>) -> [u8; RECORD_LEN as usize] {
+    let mut record = [0u8; RECORD_LEN as usize];
+    record[0..8].copy_from_slice(&line.to_le_bytes());
+    match outcome {
+        Ok(transaction) => {
+            record[8..16].copy_from_slice(&transaction.id().to_le_bytes());
+            record[16..18].copy_from_slice(&transaction.client_id().to_le_bytes());
+            record[18] = KINDS.iter().position(|&kind| kind == transaction.kind()).unwrap() as u8;
+            record[19..22].copy_from_slice(transaction.currency().code().as_bytes());
+            record[22..30].copy_from_slice(&transaction.amount().fx4().to_le_bytes());
+        }
+        Err(error) => {
+            record[8] = ERRORS.iter().position(|&e| e == error).unwrap() as u8;
+            record[18] = REFUSED;
+        }
+    }
+    let crc = crc32(&record[..30]);
+    record[30..34].copy_from_slice(&crc.to_le_bytes());
+    record
+}
+
+/// line, with the transaction or the reason it was refused; `None` if the record is broken
+fn decode(record: &[u8; RECORD_LEN as usize]) -> Option<(u64, Result<Transaction, TransactionError>)> {
+    if crc32(&record[..30]).to_le_bytes() != record[30..34] { return None; }
+    let line = u64::from_le_bytes(record[0..8].try_into().unwrap());
+    if record[18] == REFUSED { return Some((line, Err(*ERRORS.get(record[8] as usize)?))); }
+    let id = u64::from_le_bytes(record[8..16].try_into().unwrap());
+    let client_id = u16::from_le_bytes(record[16..18].try_into().unwrap());
+    let kind = *KINDS.get(record[18] as usize)?;
+    let currency = std::str::from_utf8(&record[19..22]).ok()?.parse().ok()?;
+    let amount = Amount::from_fx4(i64::from_le_bytes(record[22..30].try_into().unwrap()));
+    Some((line, Ok(Transaction::new(id, client_id, kind, amount, currency))))
+}
+
+/// CRC-32 (IEEE), bit by bit; fast enough next to the disk I/O
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u32, |crc, _| if crc & 1 == 1 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::path::PathBuf;
+    use std::{env, fs, process};
+
+    use crate::journal::{crc32, decode, encode, Error, FsyncPolicy, Journal, HEADER_LEN, KINDS, RECORD_LEN};
+    use crate::{Ledger, Transaction, TransactionKind};
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("journal-{}-{}", process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn transactions() -> Vec<Transaction> {
+        vec![
+            Transaction::new(1, 1, TransactionKind::Deposit, "10".parse().unwrap(), "EUR".parse().unwrap()),
+            Transaction::new(2, 1, TransactionKind::Withdrawal, "2.5".parse().unwrap(), "EUR".parse().unwrap()),
+            Transaction::new(3, 2, TransactionKind::Deposit, "7".parse().unwrap(), Default::default()),
+            Transaction::new(3, 2, TransactionKind::Dispute, "0".parse().unwrap(), Default::default()),
+        ]
+    }
+
+    fn journaled(path: &PathBuf) -> Ledger {
+        let mut ledger = Ledger::new();
+        let mut journal = Journal::open(path, FsyncPolicy::Always, &mut ledger).unwrap();
+        for (line, transaction) in transactions().into_iter().enumerate() {
+            ledger.mutate(transaction).unwrap();
+            journal.append(line as u64 + 2, &transaction).unwrap();
+        }
+        ledger
+    }
+
+    #[test]
+    fn checksum() {
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+    }
+
+    #[test]
+    fn kinds() {
+        for kind in KINDS {
+            let transaction = Transaction::new(1, 2, kind, "3".parse().unwrap(), Default::default());
+            assert_eq!(decode(&encode(4, Ok(&transaction))).unwrap().1.unwrap().kind(), kind);
+        }
+    }
+
+    #[test]
+    fn replay() {
+        let path = temp_path("replay");
+        let expected = journaled(&path);
+
+        let mut ledger = Ledger::new();
+        let journal = Journal::open(&path, FsyncPolicy::Never, &mut ledger).unwrap();
+        assert_eq!(journal.last_line(), 5);
+        assert_eq!(*ledger, *expected);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn finish() {
+        let path = temp_path("finish");
+        journaled(&path);
+        let mut ledger = Ledger::new();
+        Journal::open(&path, FsyncPolicy::Never, &mut ledger).unwrap().finish().unwrap();
+        assert!(!path.exists());
+
+        // a new run starts from scratch
+        let journal = Journal::open(&path, FsyncPolicy::Never, &mut Ledger::new()).unwrap();
+        assert_eq!(journal.last_line(), 0);
+        journal.finish().unwrap();
+    }
+
+    #[test]
+    fn torn_tail() {
+        let path = temp_path("torn_tail");
+        journaled(&path);
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(&[1, 2, 3]).unwrap();
+
+        let mut ledger = Ledger::new();
+        let mut journal = Journal::open(&path, FsyncPolicy::Always, &mut ledger).unwrap();
+        assert_eq!(journal.last_line(), 5);
+        assert_eq!(fs::metadata(&path).unwrap().len(), HEADER_LEN + 4 * RECORD_LEN);
+
+        // appends after the truncated tail are replayed
+        let transaction = Transaction::new(4, 2, TransactionKind::Deposit, "1".parse().unwrap(), Default::default());
+        ledger.mutate(transaction).unwrap();
+        journal.append(6, &transaction).unwrap();
+        drop(journal);
+        let mut replayed = Ledger::new();
+        assert_eq!(Journal::open(&path, FsyncPolicy::Never, &mut replayed).unwrap().last_line(), 6);
+        assert_eq!(*replayed, *ledger);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn corrupted() {
+        let path = temp_path("corrupted");
+        journaled(&path);
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[(HEADER_LEN + RECORD_LEN + 10) as usize] ^= 0xff;
+        fs::write(&path, &bytes).unwrap();
+
+        let result = Journal::open(&path, FsyncPolicy::Never, &mut Ledger::new());
+        assert!(matches!(result, Err(Error::Corrupted(offset)) if offset == HEADER_LEN + RECORD_LEN));
+
+        fs::write(&path, b"not a journal").unwrap();
+        assert!(matches!(Journal::open(&path, FsyncPolicy::Never, &mut Ledger::new()), Err(Error::Corrupted(0))));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,15 +1,25 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::{fmt, io};
 use std::ops::Deref;
 use std::str::FromStr;
+use std::sync::Arc;
 
 use serde::{Deserialize, Serialize};
 
+use crate::amount;
 use crate::amount::{Amount, ZERO};
-use crate::ledger::TransactionError::{ClientLocked, NegativeBalance, NegativeTransaction, ReferencedTransactionNonexistent};
+use crate::ledger::AccountStatus::{Active, Closed, Frozen, Locked};
+use crate::ledger::TransactionError::{AlreadyDisputed, ClientClosed, ClientFrozen, ClientLocked, ClientNonexistent, ClientNotSettled, InvalidStatusChange, DisputeAlreadySettled, DisputeWindowExpired, DuplicateTransaction, EvictedTransactionId, NegativeBalance, NegativeTransaction, NotDisputed, ReferencedTransactionClientMismatch, ReferencedTransactionNonexistent, WithdrawalNotDisputable, InvalidDisputeAmount};
 use crate::ledger::TransactionKindConversionError::NonExistentValue;
-use crate::TransactionKind::{Chargeback, Deposit, Dispute, Resolve, Withdrawal};
+use crate::ledger::TransactionState::{ChargedBack, Disputed, Processed, Resolved};
+use crate::money::Currency;
+use crate::policy::{AllowNegativeOnDispute, LedgerPolicy};
+use crate::store::{CompactStore, IdSet, StoredTransaction, TransactionStore};
+use crate::TransactionKind::{Chargeback, Close, Deposit, Dispute, Freeze, Resolve, Unlock, Withdrawal};
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+/// `Deposit` and `Withdrawal` move funds; `Dispute`, `Resolve` and `Chargeback` refer to an earlier deposit or withdrawal;
+/// `Unlock`, `Freeze` and `Close` are admin operations on the client's `AccountStatus`
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum TransactionKind {
     Deposit,
@@ -17,11 +27,23 @@ pub enum TransactionKind {
     Dispute,
     Resolve,
     Chargeback,
+    Unlock,
+    /// with the `LockMode` to freeze with, `LedgerConfig::freeze_lock` if `None`
+    Freeze(Option<LockMode>),
+    Close,
 }
 
 #[derive(Debug)]
 pub enum TransactionKindConversionError {
-    NonExistentValue
+    NonExistentValue(String)
+}
+
+impl fmt::Display for TransactionKindConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NonExistentValue(s) => { write!(f, "unknown transaction type: '{}'", s) }
+        }
+    }
 }
 
 impl FromStr for TransactionKind {
@@ -34,105 +56,501 @@ impl FromStr for TransactionKind {
             "dispute" => { Ok(Dispute) }
             "resolve" => { Ok(Resolve) }
             "chargeback" => { Ok(Chargeback) }
-            _ => { Err(NonExistentValue) }
+            "unlock" => { Ok(Unlock) }
+            "freeze" => { Ok(Freeze(None)) }
+            "close" => { Ok(Close) }
+            _ => {
+                match s.split_once(':') {
+                    Some(("freeze", mode)) => { LockMode::from_name(mode).map(|mode| Freeze(Some(mode))).ok_or(NonExistentValue(s.to_string())) }
+                    _ => { Err(NonExistentValue(s.to_string())) }
+                }
+            }
         }
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+/// as in the input, e.g. `freeze:debits`
+impl fmt::Display for TransactionKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Deposit => { "deposit" }
+            Withdrawal => { "withdrawal" }
+            Dispute => { "dispute" }
+            Resolve => { "resolve" }
+            Chargeback => { "chargeback" }
+            Unlock => { "unlock" }
+            Freeze(None) => { "freeze" }
+            Freeze(Some(mode)) => { return write!(f, "freeze:{}", mode.name()); }
+            Close => { "close" }
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// a single input transaction; for disputes, resolves and chargebacks `id` refers to the disputed transaction,
+/// for admin operations it only identifies the operation
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Transaction {
     id: u64,
     client_id: u16,
     kind: TransactionKind,
     amount: Amount,
+    currency: Currency,
 }
 
 impl Transaction {
-    pub fn new(id: u64, client_id: u16, kind: TransactionKind, amount: Amount) -> Transaction {
-        Transaction { id, client_id, kind, amount }
+    pub fn new(id: u64, client_id: u16, kind: TransactionKind, amount: Amount, currency: Currency) -> Transaction {
+        Transaction { id, client_id, kind, amount, currency }
     }
+
+    pub fn id(&self) -> u64 { self.id }
+    pub fn client_id(&self) -> u16 { self.client_id }
+    pub fn kind(&self) -> TransactionKind { self.kind }
+    pub fn amount(&self) -> Amount { self.amount }
+    pub fn currency(&self) -> Currency { self.currency }
 }
 
-#[derive(Debug)]
+/// reason for refusing a transaction; see `Display` for a human-readable description
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum TransactionError {
     NegativeBalance,
     NegativeTransaction,
     ClientLocked,
+    ClientFrozen,
+    ClientClosed,
+    ClientNonexistent,
+    InvalidStatusChange,
+    ClientNotSettled,
     ReferencedTransactionNonexistent,
+    ReferencedTransactionClientMismatch,
+    AlreadyDisputed,
+    NotDisputed,
+    DisputeAlreadySettled,
+    DuplicateTransaction,
+    /// the id belongs to a transaction evicted past its dispute window, so a replay can't be told from a new transaction
+    EvictedTransactionId,
+    DisputeWindowExpired,
+    WithdrawalNotDisputable,
+    InvalidDisputeAmount,
+    Overflow,
+    /// not a refusal: the `TransactionStore` failed. Either nothing was applied, or the transaction was, but evicting
+    /// transactions past their dispute window wasn't finished
+    Store(io::ErrorKind),
 }
 
-#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
-pub struct Client {
-    id: u16,
-    available: Amount,
-    held: Amount,
-    locked: bool,
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            TransactionError::Store(kind) => { return write!(f, "transaction store failed: {}", kind); }
+            NegativeBalance => { "insufficient available funds" }
+            NegativeTransaction => { "transaction amount is negative" }
+            ClientLocked => { "client is locked" }
+            ClientFrozen => { "client is frozen" }
+            ClientClosed => { "client is closed" }
+            ClientNonexistent => { "client does not exist" }
+            InvalidStatusChange => { "operation does not apply to the client's status" }
+            ClientNotSettled => { "client has held funds, or a negative balance" }
+            ReferencedTransactionNonexistent => { "referenced transaction does not exist" }
+            ReferencedTransactionClientMismatch => { "referenced transaction belongs to another client" }
+            AlreadyDisputed => { "referenced transaction is already disputed" }
+            NotDisputed => { "referenced transaction is not disputed" }
+            DisputeAlreadySettled => { "dispute of referenced transaction is already settled" }
+            DuplicateTransaction => { "transaction id is already used by a different transaction" }
+            EvictedTransactionId => { "transaction id is used by a transaction past its dispute window" }
+            DisputeWindowExpired => { "referenced transaction is past its dispute window" }
+            WithdrawalNotDisputable => { "withdrawals can't be disputed" }
+            InvalidDisputeAmount => { "amount exceeds what's left to dispute, or to settle, of referenced transaction" }
+            TransactionError::Overflow => { "amount out of range" }
+        };
+        write!(f, "{}", reason)
+    }
 }
 
-impl Client {
-    fn new(id: u16) -> Client {
-        Client {
-            id,
-            ..Default::default()
+impl From<io::Error> for TransactionError {
+    fn from(error: io::Error) -> Self {
+        TransactionError::Store(error.kind())
+    }
+}
+
+// checked arithmetic is the only source of `amount::Error` in a mutation
+impl From<amount::Error> for TransactionError {
+    fn from(_error: amount::Error) -> Self {
+        TransactionError::Overflow
+    }
+}
+
+/// dispute lifecycle of a stored (deposit or withdrawal) transaction:
+/// `Processed` -> `Disputed` -> `Resolved` | `ChargedBack`, the latter two telling how the last dispute was settled.
+/// Disputes can cover part of a transaction, see `DisputedAmounts`
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransactionState {
+    Processed,
+    /// some of it is under dispute
+    Disputed,
+    Resolved,
+    ChargedBack,
+}
+
+/// how much of a stored transaction is under dispute, and how much was settled; a settled part can't be disputed again
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub struct DisputedAmounts {
+    open: Amount,
+    settled: Amount,
+}
+
+impl DisputedAmounts {
+    /// amounts of a transaction of `total` in `state`, if it was only ever disputed as a whole
+    pub fn of(state: TransactionState, total: Amount) -> Self {
+        match state {
+            Processed => { DisputedAmounts::default() }
+            Disputed => { DisputedAmounts { open: total, settled: *ZERO } }
+            Resolved | ChargedBack => { DisputedAmounts { open: *ZERO, settled: total } }
         }
     }
 
-    pub fn id(&self) -> u16 { self.id }
+    pub fn open(&self) -> Amount { self.open }
+    pub fn settled(&self) -> Amount { self.settled }
+
+    /// applies a dispute, resolve or chargeback of `amount` to a transaction of `total`; zero means all there is: the undisputed
+    /// remainder for a dispute, the open amount otherwise. Returns the amount to move, with the new amounts
+    pub fn apply(self, kind: TransactionKind, amount: Amount, total: Amount) -> Result<(Amount, Self), TransactionError> {
+        let (available, error) = match kind {
+            Deposit | Withdrawal | Unlock | Freeze(_) | Close => { return Ok((*ZERO, self)); }
+            Dispute => { (total - self.open - self.settled, if self.open > *ZERO { AlreadyDisputed } else { DisputeAlreadySettled }) }
+            Resolve | Chargeback => { (self.open, if self.settled > *ZERO { DisputeAlreadySettled } else { NotDisputed }) }
+        };
+        if available == *ZERO { return Err(error); }
+        let amount = if amount == *ZERO { available } else { amount };
+        if amount > available { return Err(InvalidDisputeAmount); }
+        match kind {
+            Dispute => { Ok((amount, DisputedAmounts { open: self.open + amount, ..self })) }
+            _ => { Ok((amount, DisputedAmounts { open: self.open - amount, settled: self.settled + amount })) }
+        }
+    }
+
+    /// state after `kind` led to these amounts
+    pub fn state(&self, kind: TransactionKind) -> TransactionState {
+        match kind {
+            _ if self.open > *ZERO => { Disputed }
+            Resolve => { Resolved }
+            Chargeback => { ChargedBack }
+            _ if self.settled > *ZERO => { Resolved }
+            _ => { Processed }
+        }
+    }
+}
+
+/// funds of a client in a single currency
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Balance {
+    available: Amount,
+    held: Amount,
+}
+
+impl Balance {
     pub fn available(&self) -> Amount { self.available }
     pub fn held(&self) -> Amount { self.held }
-    pub fn locked(&self) -> bool { self.locked }
     pub fn total(&self) -> Amount { self.available + self.held }
 
     pub fn deposit(self, amount: Amount) -> Result<Self, TransactionError> {
-        Ok(Client {
-            available: self.available + amount,
+        let available = self.available.checked_add(amount)?;
+        // sanity check: `total` must stay representable, too
+        available.checked_add(self.held)?;
+        Ok(Balance {
+            available,
             ..self
         })
     }
 
+    /// may leave `available` negative, that's up to the `LedgerPolicy`
     pub fn withdrawal(self, amount: Amount) -> Result<Self, TransactionError> {
-        if amount > self.available { return Err(NegativeBalance); }
-        Ok(Client {
-            available: self.available - amount,
+        Ok(Balance {
+            available: self.available.checked_sub(amount)?,
             ..self
         })
     }
 
+    /// may leave `available` negative, that's up to the `LedgerPolicy`
     pub fn dispute(self, amount: Amount) -> Result<Self, TransactionError> {
-        // FIXME: `available` can go negative, should add sanity check
-        Ok(Client {
-            available: self.available - amount,
-            held: self.held + amount,
-            ..self
+        Ok(Balance {
+            available: self.available.checked_sub(amount)?,
+            held: self.held.checked_add(amount)?,
         })
     }
 
+    /// only valid on a disputed transaction, see `TransactionState`
     pub fn resolve(self, amount: Amount) -> Result<Self, TransactionError> {
-        // FIXME: `held` can go negative, should add sanity check
-        Ok(Client {
-            available: self.available + amount,
-            held: self.held - amount,
-            ..self
+        Ok(Balance {
+            available: self.available.checked_add(amount)?,
+            held: self.held.checked_sub(amount)?,
         })
     }
 
+    /// only valid on a disputed transaction, see `TransactionState`
     pub fn chargeback(self, amount: Amount) -> Result<Self, TransactionError> {
-        // FIXME: `held` can go negative, should add sanity check
-        Ok(Client {
-            held: self.held - amount,
-            locked: true,
+        Ok(Balance {
+            held: self.held.checked_sub(amount)?,
             ..self
         })
     }
+
+    /// the withdrawn amount is held for the client, pending the outcome of the dispute
+    pub fn dispute_withdrawal(self, amount: Amount) -> Result<Self, TransactionError> {
+        let held = self.held.checked_add(amount)?;
+        // sanity check: `total` must stay representable, too
+        held.checked_add(self.available)?;
+        Ok(Balance {
+            held,
+            ..self
+        })
+    }
+
+    /// the withdrawal stands; only valid on a disputed transaction, see `TransactionState`
+    pub fn resolve_withdrawal(self, amount: Amount) -> Result<Self, TransactionError> {
+        Ok(Balance {
+            held: self.held.checked_sub(amount)?,
+            ..self
+        })
+    }
+
+    /// the withdrawal is reversed, refunding the client; only valid on a disputed transaction, see `TransactionState`
+    pub fn chargeback_withdrawal(self, amount: Amount) -> Result<Self, TransactionError> {
+        Ok(Balance {
+            available: self.available.checked_add(amount)?,
+            held: self.held.checked_sub(amount)?,
+        })
+    }
+}
+
+/// status of a client account; only `Active` accounts accept transactions
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccountStatus {
+    #[default]
+    Active,
+    /// by a chargeback, until unlocked
+    Locked(LockMode),
+    /// by an admin, until unlocked
+    Frozen(LockMode),
+    /// for good, with the balance refunded
+    Closed,
+}
+
+impl AccountStatus {
+    /// status after the admin operation `kind`; a `Freeze` without a mode gets the default one
+    pub fn transition(self, kind: TransactionKind) -> Result<AccountStatus, TransactionError> {
+        match (self, kind) {
+            (Closed, _) => { Err(InvalidStatusChange) }
+            (Locked(_) | Frozen(_), Unlock) => { Ok(Active) }
+            (Active, Freeze(mode)) => { Ok(Frozen(mode.unwrap_or_default())) }
+            (_, Close) => { Ok(Closed) }
+            _ => { Err(InvalidStatusChange) }
+        }
+    }
+}
+
+/// e.g. `frozen:debits`
+impl fmt::Display for AccountStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Active => { write!(f, "active") }
+            Locked(mode) => { write!(f, "locked:{}", mode.name()) }
+            Frozen(mode) => { write!(f, "frozen:{}", mode.name()) }
+            Closed => { write!(f, "closed") }
+        }
+    }
+}
+
+/// a client account, with a balance per currency
+#[derive(Debug, Default, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Client {
+    id: u16,
+    balances: BTreeMap<Currency, Balance>,
+    status: AccountStatus,
+    // charged back while frozen, so locked with this mode rather than reinstated when unlocked
+    pending_lock: Option<LockMode>,
+}
+
+/// a change of a client's status, or a chargeback that locks the client, see `Ledger::audit_trail`
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    id: u64,
+    client_id: u16,
+    kind: TransactionKind,
+    from: AccountStatus,
+    to: AccountStatus,
+    refunded: Vec<(Currency, Amount)>,
+}
+
+impl AuditEntry {
+    /// id of the transaction making the change
+    pub fn id(&self) -> u64 { self.id }
+    pub fn client_id(&self) -> u16 { self.client_id }
+    pub fn kind(&self) -> TransactionKind { self.kind }
+    pub fn from(&self) -> AccountStatus { self.from }
+    pub fn to(&self) -> AccountStatus { self.to }
+    /// balances paid out to the client on `Close`
+    pub fn refunded(&self) -> &[(Currency, Amount)] { &self.refunded }
+}
+
+impl Client {
+    fn new(id: u16) -> Client {
+        Client {
+            id,
+            ..Default::default()
+        }
+    }
+
+    pub fn id(&self) -> u16 { self.id }
+    pub fn status(&self) -> AccountStatus { self.status }
+    /// whether the client is anything but `Active`
+    pub fn locked(&self) -> bool { self.status != Active }
+    /// balance in `currency`, zero if the client never had a transaction in it
+    pub fn balance(&self, currency: Currency) -> Balance { self.balances.get(&currency).copied().unwrap_or_default() }
+    /// non-empty balances, ordered by currency
+    pub fn balances(&self) -> impl Iterator<Item=(&Currency, &Balance)> { self.balances.iter() }
+}
+
+/// how long deposits and withdrawals can be disputed; once that's over, they are evicted from the ledger
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub enum Retention {
+    #[default]
+    Forever,
+    /// while at most this many more deposits and withdrawals are accepted
+    Transactions(u64),
+    /// for this long after they were accepted, in units of the timestamps passed to `Ledger::advance`
+    Time(u64),
+}
+
+/// what a dispute of a withdrawal does
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub enum WithdrawalDisputes {
+    /// refused with `WithdrawalNotDisputable`
+    Refuse,
+    /// holds the withdrawn amount for the client until the dispute is settled: a resolve releases it, a chargeback refunds it
+    #[default]
+    Refund,
+}
+
+/// what a locked or frozen client can still do; resolves and chargebacks always go through, so open disputes can settle and refunds reach the client
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LockMode {
+    /// no withdrawals
+    Debits,
+    /// no deposits, withdrawals nor new disputes
+    #[default]
+    Everything,
+    /// no deposits nor withdrawals, but new disputes
+    DisputesOnly,
+}
+
+const LOCK_MODES: [(LockMode, &str); 3] = [(LockMode::Debits, "debits"), (LockMode::Everything, "everything"), (LockMode::DisputesOnly, "disputes-only")];
+
+impl LockMode {
+    /// `debits`, `everything` or `disputes-only`
+    pub fn from_name(name: &str) -> Option<LockMode> { LOCK_MODES.iter().find(|(_, n)| *n == name).map(|(mode, _)| *mode) }
+    pub fn name(self) -> &'static str { LOCK_MODES.iter().find(|(mode, _)| *mode == self).unwrap().1 }
+
+    pub fn allows(self, kind: TransactionKind) -> bool {
+        matches!((self, kind), (_, Resolve | Chargeback) | (LockMode::Debits, Deposit | Dispute) | (LockMode::DisputesOnly, Dispute))
+    }
+}
+
+/// settings of a `Ledger`
+#[derive(Debug, Clone)]
+pub struct LedgerConfig {
+    pub retention: Retention,
+    pub withdrawal_disputes: WithdrawalDisputes,
+    pub policy: Arc<dyn LedgerPolicy>,
+    /// mode of clients `Locked` by a chargeback
+    pub chargeback_lock: LockMode,
+    /// mode of clients `Frozen` by a `Freeze` that doesn't name one
+    pub freeze_lock: LockMode,
 }
 
-#[derive(Debug, Default)]
-pub struct Ledger {
+impl Default for LedgerConfig {
+    fn default() -> Self {
+        LedgerConfig {
+            retention: Retention::default(),
+            withdrawal_disputes: WithdrawalDisputes::default(),
+            policy: Arc::new(AllowNegativeOnDispute),
+            chargeback_lock: LockMode::default(),
+            freeze_lock: LockMode::default(),
+        }
+    }
+}
+
+/// dispute window bookkeeping: stored transactions in the order they were accepted
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct Window {
+    // (age when accepted, id); only kept with a `Retention` other than `Forever`
+    queue: VecDeque<(u64, u64)>,
+    // ages in `queue` are timestamps rather than counts of accepted transactions
+    by_time: bool,
+    // deposits and withdrawals accepted so far
+    accepted: u64,
+    // latest timestamp passed to `advance`
+    clock: u64,
+    evicted_ids: IdSet,
+    // past their window, but kept until their dispute is settled
+    held: BTreeSet<u64>,
+}
+
+/// all clients, plus the deposits and withdrawals they can dispute, in a `TransactionStore`; dereferences to the clients by id
+#[derive(Debug, Default, Clone)]
+pub struct Ledger<S = CompactStore> {
     clients: HashMap<u16, Client>,
-    transactions: HashMap<u64, Transaction>,
+    transactions: S,
+    // amounts of transactions disputed in part, the others follow from their state
+    partial_disputes: BTreeMap<u64, DisputedAmounts>,
+    audit: Vec<AuditEntry>,
+    // client and kind of accepted admin operations, by id; follows from `audit`
+    admin_ops: HashMap<u64, (u16, TransactionKind)>,
+    config: LedgerConfig,
+    window: Window,
+}
+
+fn admin_ops(audit: &[AuditEntry]) -> impl Iterator<Item=(u64, (u16, TransactionKind))> + '_ {
+    audit.iter().filter(|e| matches!(e.kind, Unlock | Freeze(_) | Close)).map(|e| (e.id, (e.client_id, e.kind)))
+}
+
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// on-disk format of `Ledger::snapshot`, ordered by id to keep it diffable
+#[derive(Serialize, Deserialize)]
+struct Snapshot<C, T, D, W, A> {
+    version: u32,
+    clients: Vec<C>,
+    transactions: Vec<T>,
+    partial_disputes: D,
+    window: W,
+    audit: A,
+}
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Format(serde_json::Error),
+    UnsupportedVersion(u32),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Format(e) => { write!(f, "snapshot error: {}", e) }
+            SnapshotError::UnsupportedVersion(version) => { write!(f, "unsupported snapshot version: {}", version) }
+        }
+    }
 }
 
-impl Deref for Ledger {
+impl From<serde_json::Error> for SnapshotError {
+    fn from(error: serde_json::Error) -> Self {
+        SnapshotError::Format(error)
+    }
+}
+
+impl<S> Deref for Ledger<S> {
     type Target = HashMap<u16, Client>;
 
     fn deref(&self) -> &Self::Target {
@@ -145,83 +563,311 @@ impl Ledger {
         Ledger { ..Default::default() }
     }
 
-    pub fn mutate(&mut self, transaction: Transaction) -> Result<Client, TransactionError> {
-        // sanity check: transaction amount is not negative
-        if transaction.amount < *ZERO { return Err(NegativeTransaction); }
+    /// moves all clients and transactions of `ledgers` into a single ledger; meant for ledgers of disjoint sets of clients
+    pub fn merge(ledgers: Vec<Ledger>) -> Ledger {
+        let mut merged = Ledger::new();
+        let mut stores = Vec::new();
+        for ledger in ledgers {
+            merged.clients.extend(ledger.clients);
+            stores.push(ledger.transactions);
+            merged.partial_disputes.extend(ledger.partial_disputes);
+            merged.admin_ops.extend(admin_ops(&ledger.audit));
+            merged.audit.extend(ledger.audit);
+        }
+        merged.transactions = CompactStore::merge(stores);
+        merged
+    }
 
-        let old_client = match self.clients.get(&transaction.client_id) {
-            None => { Client::new(transaction.client_id) }
-            Some(x) => { x.clone() }
+    /// reads a ledger written by `snapshot`, with the default config
+    pub fn restore(reader: impl io::Read) -> Result<Ledger, SnapshotError> {
+        let snapshot: Snapshot<Client, StoredTransaction, BTreeMap<u64, DisputedAmounts>, Window, Vec<AuditEntry>> = serde_json::from_reader(reader)?;
+        if snapshot.version != SNAPSHOT_VERSION { return Err(SnapshotError::UnsupportedVersion(snapshot.version)); }
+        Ok(Ledger {
+            clients: snapshot.clients.into_iter().map(|c| (c.id, c)).collect(),
+            transactions: snapshot.transactions.into_iter().collect(),
+            partial_disputes: snapshot.partial_disputes,
+            window: snapshot.window,
+            admin_ops: admin_ops(&snapshot.audit).collect(),
+            audit: snapshot.audit,
+            ..Default::default()
+        })
+    }
+}
+
+impl<S: TransactionStore> Ledger<S> {
+    pub fn with_store(transactions: S) -> Self {
+        Ledger {
+            clients: HashMap::new(),
+            transactions,
+            partial_disputes: BTreeMap::new(),
+            audit: Vec::new(),
+            admin_ops: HashMap::new(),
+            config: LedgerConfig::default(),
+            window: Window::default(),
+        }
+    }
+
+    /// applies `config` from now on; a dispute window only covers transactions accepted while it's set,
+    /// and a shorter one than before only evicts once the next transaction is accepted, or the clock moves.
+    /// Switching between `Retention::Transactions` and `Retention::Time` restarts the window of the transactions already in it
+    pub fn with_config(mut self, config: LedgerConfig) -> Self {
+        let by_time = match config.retention {
+            Retention::Forever => { None }
+            Retention::Transactions(_) => { Some(false) }
+            Retention::Time(_) => { Some(true) }
         };
+        if let Some(by_time) = by_time.filter(|&by_time| by_time != self.window.by_time) {
+            let age = if by_time { self.window.clock } else { self.window.accepted };
+            self.window.queue.iter_mut().for_each(|(since, _)| *since = age);
+            self.window.by_time = by_time;
+        }
+        self.config = config;
+        self
+    }
 
-        // sanity check: locked clients can't do anything
-        if old_client.locked { return Err(ClientLocked); }
+    pub fn config(&self) -> &LedgerConfig { &self.config }
 
-        let new_client = match transaction.kind {
-            Deposit => {
-                old_client.deposit(transaction.amount).map(|c| {
-                    self.transactions.insert(transaction.id, transaction);
-                    c
-                })
+    /// every status change of every client, in order
+    pub fn audit_trail(&self) -> &[AuditEntry] { &self.audit }
+
+    /// in-memory copy, whatever the store
+    pub fn to_memory(&self) -> io::Result<Ledger> {
+        Ok(Ledger {
+            clients: self.clients.clone(),
+            transactions: self.transactions.to_compact()?,
+            partial_disputes: self.partial_disputes.clone(),
+            audit: self.audit.clone(),
+            admin_ops: self.admin_ops.clone(),
+            config: self.config.clone(),
+            window: self.window.clone(),
+        })
+    }
+
+    /// moves the clock of `Retention::Time` forward to `timestamp`, evicting transactions past their dispute window;
+    /// transactions are accepted at the time of the last call
+    pub fn advance(&mut self, timestamp: u64) -> io::Result<()> {
+        if timestamp <= self.window.clock { return Ok(()); }
+        self.window.clock = timestamp;
+        self.evict()
+    }
+
+    /// latest timestamp passed to `advance`
+    pub fn clock(&self) -> u64 { self.window.clock }
+
+    /// Transactions under dispute are held until the dispute is settled
+    fn evict(&mut self) -> io::Result<()> {
+        let (age, limit) = match self.config.retention {
+            Retention::Forever => { return Ok(()); }
+            Retention::Transactions(n) => { (self.window.accepted, n) }
+            Retention::Time(duration) => { (self.window.clock, duration) }
+        };
+        // dequeued once evicted or held, so a store error leaves the rest for the next call
+        while let Some(&(since, id)) = self.window.queue.front() {
+            if age.checked_sub(since).map_or(true, |elapsed| elapsed <= limit) { break; }
+            if self.transactions.get(id)?.is_some_and(|t| t.state() == Disputed) {
+                self.window.held.insert(id);
+            } else {
+                self.evict_id(id)?;
             }
-            Withdrawal => {
-                old_client.withdrawal(transaction.amount).map(|c| {
-                    self.transactions.insert(transaction.id, transaction);
-                    c
-                })
+            self.window.queue.pop_front();
+        }
+        Ok(())
+    }
+
+    fn evict_id(&mut self, id: u64) -> io::Result<()> {
+        self.transactions.remove(id)?;
+        self.partial_disputes.remove(&id);
+        self.window.evicted_ids.insert(id);
+        Ok(())
+    }
+
+    /// writes all clients and disputable transactions, with their dispute state, as versioned JSON
+    pub fn snapshot(&self, writer: impl io::Write) -> Result<(), SnapshotError> {
+        let mut clients: Vec<&Client> = self.clients.values().collect();
+        clients.sort_by_key(|c| c.id);
+        let mut transactions: Vec<StoredTransaction> = self.transactions.iter().collect::<io::Result<_>>().map_err(serde_json::Error::io)?;
+        transactions.sort_by_key(|t| t.transaction().id);
+        serde_json::to_writer(writer, &Snapshot { version: SNAPSHOT_VERSION, clients, transactions, partial_disputes: &self.partial_disputes, window: &self.window, audit: &self.audit })?;
+        Ok(())
+    }
+
+    /// applies `transaction` to the balance in its currency; disputes, resolves and chargebacks apply to the currency of the
+    /// referenced transaction, for the amount given, or all of it if zero, see `DisputedAmounts::apply`. With a dispute window, ids of evicted transactions can't be used again
+    pub fn mutate(&mut self, transaction: Transaction) -> Result<&Client, TransactionError> {
+        // sanity check: transaction amount is not negative
+        if transaction.amount < *ZERO { return Err(NegativeTransaction); }
+
+        // replayed deposits and withdrawals are a no-op, but their ids can't be reused for anything else
+        if let Deposit | Withdrawal = transaction.kind {
+            if let Some(stored) = self.transactions.get(transaction.id)? {
+                if *stored.transaction() != transaction { return Err(DuplicateTransaction); }
+                return self.clients.get(&transaction.client_id).ok_or(DuplicateTransaction);
             }
-            // FIXME: implement a state machine to check if operation can be carried out; e.g. no `dispute` on `withdrawal`, or no `resolve` if there was not even a `dispute`
-            Dispute => {
-                match self.transactions.get(&transaction.id) {
-                    Some(p) => { old_client.dispute(p.amount) }
-                    _ => { Err(ReferencedTransactionNonexistent) }
-                }
+            if self.window.evicted_ids.contains(transaction.id) { return Err(EvictedTransactionId); }
+        }
+
+        if let Unlock | Freeze(_) | Close = transaction.kind { return self.administer(transaction); }
+
+        let old_client = self.clients.get(&transaction.client_id);
+
+        // sanity check: locked and frozen clients can only do what their `LockMode` allows, closed ones nothing
+        match old_client.map(|c| c.status) {
+            Some(Locked(mode)) if !mode.allows(transaction.kind) => { return Err(ClientLocked); }
+            Some(Frozen(mode)) if !mode.allows(transaction.kind) => { return Err(ClientFrozen); }
+            Some(Closed) => { return Err(ClientClosed); }
+            _ => {}
+        }
+
+        let old_balance = |currency| old_client.map(|c| c.balance(currency)).unwrap_or_default();
+        // new state and amounts of the referenced transaction
+        let mut settlement = None;
+        // a chargeback of a deposit reverses fraudulent funds, while one of a withdrawal refunds the client
+        let mut lock = false;
+        let (currency, new_balance) = match transaction.kind {
+            Deposit => { (transaction.currency, old_balance(transaction.currency).deposit(transaction.amount)?) }
+            Withdrawal => { (transaction.currency, old_balance(transaction.currency).withdrawal(transaction.amount)?) }
+            _ => {
+                let stored = self.transactions.get(transaction.id)?
+                    .ok_or(if self.window.evicted_ids.contains(transaction.id) { DisputeWindowExpired } else { ReferencedTransactionNonexistent })?;
+                let referenced = stored.transaction();
+                // sanity check: clients can only refer to their own transactions
+                if referenced.client_id != transaction.client_id { return Err(ReferencedTransactionClientMismatch); }
+                if referenced.kind == Withdrawal && self.config.withdrawal_disputes == WithdrawalDisputes::Refuse { return Err(WithdrawalNotDisputable); }
+                let amounts = self.partial_disputes.get(&transaction.id).copied().unwrap_or(DisputedAmounts::of(stored.state(), referenced.amount));
+                let (amount, new_amounts) = amounts.apply(transaction.kind, transaction.amount, referenced.amount)?;
+                let new_state = new_amounts.state(transaction.kind);
+                let currency = referenced.currency;
+                let balance = old_balance(currency);
+                let new_balance = match (transaction.kind, referenced.kind) {
+                    (Dispute, Withdrawal) => { balance.dispute_withdrawal(amount) }
+                    (Resolve, Withdrawal) => { balance.resolve_withdrawal(amount) }
+                    (_, Withdrawal) => { balance.chargeback_withdrawal(amount) }
+                    (Dispute, _) => { balance.dispute(amount) }
+                    (Resolve, _) => { balance.resolve(amount) }
+                    _ => { balance.chargeback(amount) }
+                }?;
+                lock = transaction.kind == Chargeback && referenced.kind == Deposit;
+                settlement = Some((new_state, (new_amounts != DisputedAmounts::of(new_state, referenced.amount)).then_some(new_amounts)));
+                (currency, new_balance)
             }
-            Resolve => {
-                match self.transactions.get(&transaction.id) {
-                    Some(p) => { old_client.resolve(p.amount) }
-                    _ => { Err(ReferencedTransactionNonexistent) }
-                }
+        };
+
+        if new_balance.available < old_balance(currency).available
+            && !self.config.policy.allows(transaction.client_id, transaction.kind, currency, &new_balance) {
+            return Err(NegativeBalance);
+        }
+        // the one store write before anything changes, so if it fails, nothing is applied
+        match settlement {
+            Some((state, _)) => { self.transactions.set_state(transaction.id, state)?; }
+            None => { self.transactions.insert(StoredTransaction::new(transaction, Processed))?; }
+        }
+        if let Some((_, amounts)) = settlement {
+            match amounts {
+                Some(amounts) => { self.partial_disputes.insert(transaction.id, amounts); }
+                None => { self.partial_disputes.remove(&transaction.id); }
             }
-            Chargeback => {
-                match self.transactions.get(&transaction.id) {
-                    Some(p) => { old_client.chargeback(p.amount) }
-                    _ => { Err(ReferencedTransactionNonexistent) }
-                }
+        }
+        let mut queued = false;
+        if let Deposit | Withdrawal = transaction.kind {
+            self.window.accepted += 1;
+            let age = match self.config.retention {
+                Retention::Forever => { None }
+                Retention::Transactions(_) => { Some(self.window.accepted) }
+                Retention::Time(_) => { Some(self.window.clock) }
+            };
+            if let Some(age) = age {
+                self.window.queue.push_back((age, transaction.id));
+                queued = true;
             }
-        }?;
+        }
 
-        self.clients.insert(transaction.client_id, new_client);
-        Ok(new_client)
+        let client = self.clients.entry(transaction.client_id).or_insert_with(|| Client::new(transaction.client_id));
+        client.balances.insert(currency, new_balance);
+        // a frozen client stays frozen, and is locked once unlocked
+        if lock {
+            let mode = self.config.chargeback_lock;
+            let status = if let Frozen(_) = client.status { client.status } else { Locked(mode) };
+            client.pending_lock = matches!(status, Frozen(_)).then_some(mode);
+            self.audit.push(AuditEntry { id: transaction.id, client_id: client.id, kind: Chargeback, from: client.status, to: status, refunded: Vec::new() });
+            client.status = status;
+        }
+
+        // evictions come last, with the transaction applied whatever happens to them
+        if settlement.is_some_and(|(state, _)| state != Disputed) && self.window.held.contains(&transaction.id) {
+            self.evict_id(transaction.id)?;
+            self.window.held.remove(&transaction.id);
+        }
+        if queued { self.evict()?; }
+        Ok(&self.clients[&transaction.client_id])
+    }
+
+    /// changes the status of an existing client; closing requires all disputes settled and no debt, and pays out the balance
+    fn administer(&mut self, transaction: Transaction) -> Result<&Client, TransactionError> {
+        let kind = match transaction.kind {
+            Freeze(None) => { Freeze(Some(self.config.freeze_lock)) }
+            kind => { kind }
+        };
+        // like deposits and withdrawals, a replay is a no-op, but the id can't be reused
+        if let Some(&op) = self.admin_ops.get(&transaction.id) {
+            if op != (transaction.client_id, kind) { return Err(DuplicateTransaction); }
+            return self.clients.get(&transaction.client_id).ok_or(DuplicateTransaction);
+        }
+        let client = self.clients.get_mut(&transaction.client_id).ok_or(ClientNonexistent)?;
+        let mut status = client.status.transition(kind)?;
+        if let (Active, Some(mode)) = (status, client.pending_lock) { status = Locked(mode); }
+        let mut refunded = Vec::new();
+        if status == Closed {
+            if client.balances.values().any(|b| b.held != *ZERO || b.available < *ZERO) { return Err(ClientNotSettled); }
+            for (currency, balance) in client.balances.iter_mut().filter(|(_, b)| b.available > *ZERO) {
+                refunded.push((*currency, balance.available));
+                *balance = Balance::default();
+            }
+        }
+        self.audit.push(AuditEntry { id: transaction.id, client_id: client.id, kind, from: client.status, to: status, refunded });
+        self.admin_ops.insert(transaction.id, (transaction.client_id, kind));
+        client.status = status;
+        client.pending_lock = None;
+        Ok(client)
     }
 }
 
-// only basic test coverage here; it's a lot easier to test complex functionality end-to-end, from `main.rs`
+// only basic test coverage here; it's a lot easier to test complex functionality end-to-end, from `api.rs`
 #[cfg(test)]
 mod tests {
+    use std::collections::BTreeMap;
+
     use crate::{Client, Ledger, Transaction, TransactionKind};
+    use crate::amount::ZERO;
+    use crate::ledger::{AccountStatus, Balance, DisputedAmounts, LedgerConfig, LockMode, Retention, SnapshotError, TransactionError, WithdrawalDisputes};
+    use crate::ledger::TransactionState::{ChargedBack, Disputed, Processed, Resolved};
+    use crate::money::Currency;
+
+    fn client(id: u16, available: &str, held: &str, locked: bool) -> Client {
+        let balance = Balance { available: available.parse().unwrap(), held: held.parse().unwrap() };
+        Client { id, balances: BTreeMap::from([(Currency::default(), balance)]), status: if locked { AccountStatus::Locked(LockMode::Everything) } else { AccountStatus::Active }, pending_lock: None }
+    }
 
     #[test]
     fn single_deposit() {
         let mut ledger = Ledger::new();
-        ledger.mutate(Transaction { id: 0, client_id: 0, kind: TransactionKind::Deposit, amount: "12.5".parse().unwrap() }).expect("");
+        ledger.mutate(Transaction { id: 0, client_id: 0, kind: TransactionKind::Deposit, amount: "12.5".parse().unwrap(), currency: Currency::default() }).expect("");
         let mut iter = ledger.iter();
-        assert_eq!(iter.next().unwrap(), (&0u16, &Client { id: 0, available: "12.5".parse().unwrap(), held: "0".parse().unwrap(), locked: false }))
+        assert_eq!(iter.next().unwrap(), (&0u16, &client(0, "12.5", "0", false)))
     }
 
     #[test]
     fn multi_deposit() {
         let mut ledger = Ledger::new();
-        ledger.mutate(Transaction { id: 0, client_id: 0, kind: TransactionKind::Deposit, amount: "12.5".parse().unwrap() }).expect("");
-        ledger.mutate(Transaction { id: 1, client_id: 0, kind: TransactionKind::Deposit, amount: "7.5".parse().unwrap() }).expect("");
+        ledger.mutate(Transaction { id: 0, client_id: 0, kind: TransactionKind::Deposit, amount: "12.5".parse().unwrap(), currency: Currency::default() }).expect("");
+        ledger.mutate(Transaction { id: 1, client_id: 0, kind: TransactionKind::Deposit, amount: "7.5".parse().unwrap(), currency: Currency::default() }).expect("");
         let mut iter = ledger.iter();
-        assert_eq!(iter.next().unwrap(), (&0u16, &Client { id: 0, available: "20".parse().unwrap(), held: "0".parse().unwrap(), locked: false }))
+        assert_eq!(iter.next().unwrap(), (&0u16, &client(0, "20", "0", false)))
     }
 
     #[test]
     fn single_withdraw() {
         let mut ledger = Ledger::new();
-        ledger.mutate(Transaction { id: 0, client_id: 0, kind: TransactionKind::Withdrawal, amount: "12.5".parse().unwrap() }).expect_err("");
+        ledger.mutate(Transaction { id: 0, client_id: 0, kind: TransactionKind::Withdrawal, amount: "12.5".parse().unwrap(), currency: Currency::default() }).expect_err("");
         let mut iter = ledger.iter();
         assert_eq!(iter.next(), None);
     }
@@ -229,26 +875,401 @@ mod tests {
     #[test]
     fn multi_deposit_withdraw() {
         let mut ledger = Ledger::new();
-        ledger.mutate(Transaction { id: 0, client_id: 0, kind: TransactionKind::Deposit, amount: "12.5".parse().unwrap() }).expect("");
-        ledger.mutate(Transaction { id: 1, client_id: 0, kind: TransactionKind::Withdrawal, amount: "7.5".parse().unwrap() }).expect("");
-        ledger.mutate(Transaction { id: 2, client_id: 0, kind: TransactionKind::Deposit, amount: "5".parse().unwrap() }).expect("");
-        ledger.mutate(Transaction { id: 3, client_id: 0, kind: TransactionKind::Deposit, amount: "-5".parse().unwrap() }).expect_err("");
+        ledger.mutate(Transaction { id: 0, client_id: 0, kind: TransactionKind::Deposit, amount: "12.5".parse().unwrap(), currency: Currency::default() }).expect("");
+        ledger.mutate(Transaction { id: 1, client_id: 0, kind: TransactionKind::Withdrawal, amount: "7.5".parse().unwrap(), currency: Currency::default() }).expect("");
+        ledger.mutate(Transaction { id: 2, client_id: 0, kind: TransactionKind::Deposit, amount: "5".parse().unwrap(), currency: Currency::default() }).expect("");
+        ledger.mutate(Transaction { id: 3, client_id: 0, kind: TransactionKind::Deposit, amount: "-5".parse().unwrap(), currency: Currency::default() }).expect_err("");
         let mut iter = ledger.iter();
-        assert_eq!(iter.next().unwrap(), (&0u16, &Client { id: 0, available: "10".parse().unwrap(), held: "0".parse().unwrap(), locked: false }))
+        assert_eq!(iter.next().unwrap(), (&0u16, &client(0, "10", "0", false)))
     }
 
     #[test]
     fn multi_client() {
         let mut ledger = Ledger::new();
-        ledger.mutate(Transaction { id: 0, client_id: 5, kind: TransactionKind::Deposit, amount: "5".parse().unwrap() }).expect("");
-        ledger.mutate(Transaction { id: 1, client_id: 10, kind: TransactionKind::Withdrawal, amount: "10".parse().unwrap() }).expect_err("");
-        ledger.mutate(Transaction { id: 2, client_id: 5, kind: TransactionKind::Withdrawal, amount: "2".parse().unwrap() }).expect("");
-        ledger.mutate(Transaction { id: 3, client_id: 3, kind: TransactionKind::Deposit, amount: "3".parse().unwrap() }).expect("");
+        ledger.mutate(Transaction { id: 0, client_id: 5, kind: TransactionKind::Deposit, amount: "5".parse().unwrap(), currency: Currency::default() }).expect("");
+        ledger.mutate(Transaction { id: 1, client_id: 10, kind: TransactionKind::Withdrawal, amount: "10".parse().unwrap(), currency: Currency::default() }).expect_err("");
+        ledger.mutate(Transaction { id: 2, client_id: 5, kind: TransactionKind::Withdrawal, amount: "2".parse().unwrap(), currency: Currency::default() }).expect("");
+        ledger.mutate(Transaction { id: 3, client_id: 3, kind: TransactionKind::Deposit, amount: "3".parse().unwrap(), currency: Currency::default() }).expect("");
 
         let mut result: Vec<&Client> = ledger.iter().map(|e| e.1).collect();
-        result.sort_by(|a, b| a.id.cmp(&b.id));
+        result.sort_by_key(|c| c.id);
 
-        assert_eq!(*result[0], Client { id: 3, available: "3".parse().unwrap(), held: "0".parse().unwrap(), locked: false });
-        assert_eq!(*result[1], Client { id: 5, available: "3".parse().unwrap(), held: "0".parse().unwrap(), locked: false });
+        assert_eq!(*result[0], client(3, "3", "0", false));
+        assert_eq!(*result[1], client(5, "3", "0", false));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn state_transitions() {
+        let total = "10".parse().unwrap();
+        let transition = |state, kind| DisputedAmounts::of(state, total).apply(kind, *ZERO, total).map(|(_, amounts)| amounts.state(kind));
+        assert_eq!(transition(Processed, TransactionKind::Dispute).unwrap(), Disputed);
+        assert_eq!(transition(Disputed, TransactionKind::Resolve).unwrap(), Resolved);
+        assert_eq!(transition(Disputed, TransactionKind::Chargeback).unwrap(), ChargedBack);
+
+        assert!(matches!(transition(Processed, TransactionKind::Resolve), Err(TransactionError::NotDisputed)));
+        assert!(matches!(transition(Processed, TransactionKind::Chargeback), Err(TransactionError::NotDisputed)));
+        assert!(matches!(transition(Disputed, TransactionKind::Dispute), Err(TransactionError::AlreadyDisputed)));
+        assert!(matches!(transition(Resolved, TransactionKind::Dispute), Err(TransactionError::DisputeAlreadySettled)));
+        assert!(matches!(transition(Resolved, TransactionKind::Chargeback), Err(TransactionError::DisputeAlreadySettled)));
+        assert!(matches!(transition(ChargedBack, TransactionKind::Resolve), Err(TransactionError::DisputeAlreadySettled)));
+    }
+
+    #[test]
+    fn partial_disputes() {
+        let transaction = |kind, amount: &str| Transaction { id: 0, client_id: 0, kind, amount: amount.parse().unwrap(), currency: Currency::default() };
+        let mut ledger = Ledger::new();
+        ledger.mutate(transaction(TransactionKind::Deposit, "10")).expect("");
+        ledger.mutate(transaction(TransactionKind::Dispute, "2.5")).expect("");
+        ledger.mutate(transaction(TransactionKind::Dispute, "3")).expect("");
+        assert_eq!(ledger[&0], client(0, "4.5", "5.5", false));
+        assert!(matches!(ledger.mutate(transaction(TransactionKind::Dispute, "5")), Err(TransactionError::InvalidDisputeAmount)));
+        assert!(matches!(ledger.mutate(transaction(TransactionKind::Resolve, "6")), Err(TransactionError::InvalidDisputeAmount)));
+
+        ledger.mutate(transaction(TransactionKind::Resolve, "2.5")).expect("");
+        assert_eq!(ledger.transactions.get(0).unwrap().state(), Disputed);
+        // the rest of the undisputed part
+        ledger.mutate(transaction(TransactionKind::Dispute, "0")).expect("");
+        assert_eq!(ledger[&0], client(0, "2.5", "7.5", false));
+        assert!(matches!(ledger.mutate(transaction(TransactionKind::Dispute, "0")), Err(TransactionError::AlreadyDisputed)));
+        assert_eq!(ledger.partial_disputes[&0], DisputedAmounts { open: "7.5".parse().unwrap(), settled: "2.5".parse().unwrap() });
+
+        ledger.mutate(transaction(TransactionKind::Chargeback, "0")).expect("");
+        assert_eq!(ledger[&0], client(0, "2.5", "0", true));
+        assert_eq!(ledger.transactions.get(0).unwrap().state(), ChargedBack);
+        // all settled, so the state says it all
+        assert!(ledger.partial_disputes.is_empty());
+    }
+
+    #[test]
+    fn repeated_dispute() {
+        let mut ledger = Ledger::new();
+        ledger.mutate(Transaction { id: 0, client_id: 0, kind: TransactionKind::Deposit, amount: "10".parse().unwrap(), currency: Currency::default() }).expect("");
+        ledger.mutate(Transaction { id: 0, client_id: 0, kind: TransactionKind::Dispute, amount: "0".parse().unwrap(), currency: Currency::default() }).expect("");
+        ledger.mutate(Transaction { id: 0, client_id: 0, kind: TransactionKind::Dispute, amount: "0".parse().unwrap(), currency: Currency::default() }).expect_err("");
+        let mut iter = ledger.iter();
+        assert_eq!(iter.next().unwrap(), (&0u16, &client(0, "0", "10", false)))
+    }
+
+    #[test]
+    fn duplicate_transaction() {
+        let mut ledger = Ledger::new();
+        ledger.mutate(Transaction { id: 0, client_id: 0, kind: TransactionKind::Deposit, amount: "10".parse().unwrap(), currency: Currency::default() }).expect("");
+        ledger.mutate(Transaction { id: 0, client_id: 0, kind: TransactionKind::Deposit, amount: "10".parse().unwrap(), currency: Currency::default() }).expect("");
+        assert!(matches!(
+            ledger.mutate(Transaction { id: 0, client_id: 0, kind: TransactionKind::Deposit, amount: "5".parse().unwrap(), currency: Currency::default() }),
+            Err(TransactionError::DuplicateTransaction)));
+        assert!(matches!(
+            ledger.mutate(Transaction { id: 0, client_id: 0, kind: TransactionKind::Withdrawal, amount: "10".parse().unwrap(), currency: Currency::default() }),
+            Err(TransactionError::DuplicateTransaction)));
+        assert!(matches!(
+            ledger.mutate(Transaction { id: 0, client_id: 1, kind: TransactionKind::Deposit, amount: "10".parse().unwrap(), currency: Currency::default() }),
+            Err(TransactionError::DuplicateTransaction)));
+        let mut iter = ledger.iter();
+        assert_eq!(iter.next().unwrap(), (&0u16, &client(0, "10", "0", false)));
+        assert_eq!(iter.next(), None);
+    }
+
+    #[test]
+    fn overflow() {
+        let mut ledger = Ledger::new();
+        ledger.mutate(Transaction { id: 0, client_id: 0, kind: TransactionKind::Deposit, amount: "900000000000000".parse().unwrap(), currency: Currency::default() }).expect("");
+        assert!(matches!(
+            ledger.mutate(Transaction { id: 1, client_id: 0, kind: TransactionKind::Deposit, amount: "900000000000000".parse().unwrap(), currency: Currency::default() }),
+            Err(TransactionError::Overflow)));
+        let mut iter = ledger.iter();
+        assert_eq!(iter.next().unwrap(), (&0u16, &client(0, "900000000000000", "0", false)));
+    }
+
+    #[test]
+    fn multi_currency() {
+        let eur: Currency = "EUR".parse().unwrap();
+        let usd: Currency = "USD".parse().unwrap();
+        let mut ledger = Ledger::new();
+        ledger.mutate(Transaction { id: 0, client_id: 0, kind: TransactionKind::Deposit, amount: "10".parse().unwrap(), currency: eur }).expect("");
+        ledger.mutate(Transaction { id: 1, client_id: 0, kind: TransactionKind::Deposit, amount: "5".parse().unwrap(), currency: usd }).expect("");
+        ledger.mutate(Transaction { id: 2, client_id: 0, kind: TransactionKind::Withdrawal, amount: "7".parse().unwrap(), currency: usd }).expect_err("");
+        ledger.mutate(Transaction { id: 3, client_id: 0, kind: TransactionKind::Withdrawal, amount: "7".parse().unwrap(), currency: eur }).expect("");
+        // the referenced transaction determines the currency
+        ledger.mutate(Transaction { id: 1, client_id: 0, kind: TransactionKind::Dispute, amount: "0".parse().unwrap(), currency: eur }).expect("");
+
+        let client = &ledger[&0];
+        assert_eq!(client.balance(eur), Balance { available: "3".parse().unwrap(), held: "0".parse().unwrap() });
+        assert_eq!(client.balance(usd), Balance { available: "0".parse().unwrap(), held: "5".parse().unwrap() });
+        assert_eq!(client.balances().count(), 2);
+    }
+
+    #[test]
+    fn status_transitions() {
+        use AccountStatus::{Active, Closed, Frozen, Locked};
+        let (locked, frozen) = (Locked(LockMode::Everything), Frozen(LockMode::Debits));
+        assert_eq!(locked.transition(TransactionKind::Unlock).unwrap(), Active);
+        assert_eq!(frozen.transition(TransactionKind::Unlock).unwrap(), Active);
+        assert_eq!(Active.transition(TransactionKind::Freeze(Some(LockMode::Debits))).unwrap(), frozen);
+        assert_eq!(Active.transition(TransactionKind::Freeze(None)).unwrap(), Frozen(LockMode::Everything));
+        for status in [Active, locked, frozen] {
+            assert_eq!(status.transition(TransactionKind::Close).unwrap(), Closed);
+        }
+
+        assert!(matches!(Active.transition(TransactionKind::Unlock), Err(TransactionError::InvalidStatusChange)));
+        assert!(matches!(frozen.transition(TransactionKind::Freeze(None)), Err(TransactionError::InvalidStatusChange)));
+        assert!(matches!(locked.transition(TransactionKind::Freeze(None)), Err(TransactionError::InvalidStatusChange)));
+        for kind in [TransactionKind::Unlock, TransactionKind::Freeze(None), TransactionKind::Close] {
+            assert!(matches!(Closed.transition(kind), Err(TransactionError::InvalidStatusChange)));
+        }
+    }
+
+    #[test]
+    fn administer() {
+        let transaction = |id, kind, amount: &str| Transaction { id, client_id: 0, kind, amount: amount.parse().unwrap(), currency: Currency::default() };
+        let mut ledger = Ledger::new();
+        assert!(matches!(ledger.mutate(transaction(100, TransactionKind::Freeze(None), "0")), Err(TransactionError::ClientNonexistent)));
+        ledger.mutate(transaction(0, TransactionKind::Deposit, "10")).expect("");
+        ledger.mutate(transaction(1, TransactionKind::Deposit, "5")).expect("");
+        ledger.mutate(transaction(1, TransactionKind::Dispute, "0")).expect("");
+        ledger.mutate(transaction(1, TransactionKind::Chargeback, "0")).expect("");
+        ledger.mutate(transaction(101, TransactionKind::Unlock, "0")).expect("");
+        ledger.mutate(transaction(2, TransactionKind::Deposit, "1")).expect("");
+
+        ledger.mutate(transaction(102, TransactionKind::Freeze(None), "0")).expect("");
+        assert!(matches!(ledger.mutate(transaction(3, TransactionKind::Deposit, "1")), Err(TransactionError::ClientFrozen)));
+        ledger.mutate(transaction(103, TransactionKind::Unlock, "0")).expect("");
+        // a redelivered admin operation is a no-op, its id can't be reused
+        ledger.mutate(transaction(102, TransactionKind::Freeze(None), "0")).expect("");
+        ledger.mutate(transaction(102, TransactionKind::Freeze(Some(LockMode::Everything)), "0")).expect("");
+        assert_eq!(ledger[&0].status(), AccountStatus::Active);
+        assert!(matches!(ledger.mutate(transaction(102, TransactionKind::Freeze(Some(LockMode::Debits)), "0")), Err(TransactionError::DuplicateTransaction)));
+        assert!(matches!(ledger.mutate(transaction(103, TransactionKind::Freeze(None), "0")), Err(TransactionError::DuplicateTransaction)));
+        ledger.mutate(transaction(0, TransactionKind::Dispute, "0")).expect("");
+        assert!(matches!(ledger.mutate(transaction(104, TransactionKind::Close, "0")), Err(TransactionError::ClientNotSettled)));
+        ledger.mutate(transaction(0, TransactionKind::Resolve, "0")).expect("");
+        ledger.mutate(transaction(104, TransactionKind::Close, "0")).expect("");
+        assert_eq!(ledger[&0].status(), AccountStatus::Closed);
+        assert_eq!(ledger[&0].balance(Currency::default()), Balance::default());
+        assert!(matches!(ledger.mutate(transaction(3, TransactionKind::Deposit, "1")), Err(TransactionError::ClientClosed)));
+        assert!(matches!(ledger.mutate(transaction(105, TransactionKind::Unlock, "0")), Err(TransactionError::InvalidStatusChange)));
+        ledger.mutate(transaction(104, TransactionKind::Close, "0")).expect("");
+        // even after a restore
+        let mut bytes = Vec::new();
+        ledger.snapshot(&mut bytes).unwrap();
+        let mut restored = Ledger::restore(bytes.as_slice()).unwrap();
+        restored.mutate(transaction(101, TransactionKind::Unlock, "0")).expect("");
+        assert!(matches!(restored.mutate(transaction(101, TransactionKind::Close, "0")), Err(TransactionError::DuplicateTransaction)));
+
+        let trail: Vec<(u64, AccountStatus, AccountStatus)> = ledger.audit_trail().iter().map(|e| (e.id(), e.from(), e.to())).collect();
+        assert_eq!(trail, vec![
+            (1, AccountStatus::Active, AccountStatus::Locked(LockMode::Everything)),
+            (101, AccountStatus::Locked(LockMode::Everything), AccountStatus::Active),
+            (102, AccountStatus::Active, AccountStatus::Frozen(LockMode::Everything)),
+            (103, AccountStatus::Frozen(LockMode::Everything), AccountStatus::Active),
+            (104, AccountStatus::Active, AccountStatus::Closed),
+        ]);
+        assert_eq!(ledger.audit_trail()[4].refunded(), &[(Currency::default(), "11".parse().unwrap())]);
+    }
+
+    #[test]
+    fn lock_modes() {
+        let transaction = |id, kind, amount: &str| Transaction { id, client_id: 0, kind, amount: amount.parse().unwrap(), currency: Currency::default() };
+        for (mode, deposit, withdrawal, dispute) in [(LockMode::Debits, true, false, true), (LockMode::Everything, false, false, false), (LockMode::DisputesOnly, false, false, true)] {
+            let mut ledger = Ledger::new().with_config(LedgerConfig { chargeback_lock: mode, ..Default::default() });
+            for (id, amount) in [(1, "10"), (2, "5"), (3, "3")] {
+                ledger.mutate(transaction(id, TransactionKind::Deposit, amount)).expect("");
+            }
+            ledger.mutate(transaction(2, TransactionKind::Dispute, "0")).expect("");
+            ledger.mutate(transaction(3, TransactionKind::Dispute, "0")).expect("");
+            ledger.mutate(transaction(2, TransactionKind::Chargeback, "0")).expect("");
+            assert!(ledger[&0].locked());
+
+            // the open dispute settles whatever the mode
+            ledger.mutate(transaction(3, TransactionKind::Resolve, "0")).expect("");
+            assert_eq!(ledger.mutate(transaction(4, TransactionKind::Deposit, "1")).is_ok(), deposit, "{:?}", mode);
+            assert_eq!(ledger.mutate(transaction(5, TransactionKind::Withdrawal, "1")).is_ok(), withdrawal, "{:?}", mode);
+            assert_eq!(ledger.mutate(transaction(1, TransactionKind::Dispute, "0")).is_ok(), dispute, "{:?}", mode);
+            assert!(matches!(ledger.mutate(transaction(5, TransactionKind::Withdrawal, "1")), Err(TransactionError::ClientLocked)));
+            assert_eq!(ledger.audit_trail().len(), 1);
+        }
+
+        // a frozen client charged back stays frozen, and is locked when unlocked
+        let mut ledger = Ledger::new().with_config(LedgerConfig { chargeback_lock: LockMode::Debits, freeze_lock: LockMode::DisputesOnly, ..Default::default() });
+        ledger.mutate(transaction(1, TransactionKind::Deposit, "10")).expect("");
+        ledger.mutate(transaction(100, TransactionKind::Freeze(None), "0")).expect("");
+        assert!(matches!(ledger.mutate(transaction(2, TransactionKind::Deposit, "1")), Err(TransactionError::ClientFrozen)));
+        ledger.mutate(transaction(1, TransactionKind::Dispute, "0")).expect("");
+        ledger.mutate(transaction(1, TransactionKind::Chargeback, "0")).expect("");
+        assert_eq!(ledger[&0].status(), AccountStatus::Frozen(LockMode::DisputesOnly));
+        ledger.mutate(transaction(101, TransactionKind::Unlock, "0")).expect("");
+        assert_eq!(ledger[&0].status(), AccountStatus::Locked(LockMode::Debits));
+        ledger.mutate(transaction(102, TransactionKind::Unlock, "0")).expect("");
+        assert_eq!(ledger[&0].status(), AccountStatus::Active);
+
+        let trail: Vec<(u64, TransactionKind, AccountStatus, AccountStatus)> = ledger.audit_trail().iter().map(|e| (e.id(), e.kind(), e.from(), e.to())).collect();
+        assert_eq!(trail, vec![
+            (100, TransactionKind::Freeze(Some(LockMode::DisputesOnly)), AccountStatus::Active, AccountStatus::Frozen(LockMode::DisputesOnly)),
+            (1, TransactionKind::Chargeback, AccountStatus::Frozen(LockMode::DisputesOnly), AccountStatus::Frozen(LockMode::DisputesOnly)),
+            (101, TransactionKind::Unlock, AccountStatus::Frozen(LockMode::DisputesOnly), AccountStatus::Locked(LockMode::Debits)),
+            (102, TransactionKind::Unlock, AccountStatus::Locked(LockMode::Debits), AccountStatus::Active),
+        ]);
+
+        // the mode is the client's: a freeze row can name its own
+        let transaction = |id, client_id, kind| Transaction { id, client_id, kind, amount: "1".parse().unwrap(), currency: Currency::default() };
+        let mut ledger = Ledger::new();
+        ledger.mutate(transaction(1, 1, TransactionKind::Deposit)).expect("");
+        ledger.mutate(transaction(2, 2, TransactionKind::Deposit)).expect("");
+        ledger.mutate(transaction(100, 1, TransactionKind::Freeze(Some(LockMode::Debits)))).expect("");
+        ledger.mutate(transaction(101, 2, TransactionKind::Freeze(None))).expect("");
+        ledger.mutate(transaction(3, 1, TransactionKind::Deposit)).expect("");
+        assert!(matches!(ledger.mutate(transaction(4, 1, TransactionKind::Withdrawal)), Err(TransactionError::ClientFrozen)));
+        assert!(matches!(ledger.mutate(transaction(5, 2, TransactionKind::Deposit)), Err(TransactionError::ClientFrozen)));
+        assert_eq!(ledger[&1].status(), AccountStatus::Frozen(LockMode::Debits));
+        assert_eq!(ledger[&2].status(), AccountStatus::Frozen(LockMode::Everything));
+    }
+
+    #[test]
+    fn withdrawal_dispute() {
+        let transaction = |id, kind| Transaction { id, client_id: 0, kind, amount: if id == 0 { "10" } else { "4" }.parse().unwrap(), currency: Currency::default() };
+        let ledger = |config: LedgerConfig, settlement: Option<TransactionKind>| {
+            let mut ledger = Ledger::new().with_config(config);
+            ledger.mutate(transaction(0, TransactionKind::Deposit)).expect("");
+            ledger.mutate(transaction(1, TransactionKind::Withdrawal)).expect("");
+            ledger.mutate(transaction(1, TransactionKind::Dispute))?;
+            if let Some(kind) = settlement { ledger.mutate(transaction(1, kind))?; }
+            Ok::<_, TransactionError>(ledger[&0].clone())
+        };
+
+        // the withdrawn 4 is held for the client, then either released or refunded
+        assert_eq!(ledger(LedgerConfig::default(), None).unwrap(), client(0, "6", "4", false));
+        assert_eq!(ledger(LedgerConfig::default(), Some(TransactionKind::Resolve)).unwrap(), client(0, "6", "0", false));
+        // and, unlike a chargeback of a deposit, a refund doesn't lock the client
+        assert_eq!(ledger(LedgerConfig::default(), Some(TransactionKind::Chargeback)).unwrap(), client(0, "10", "0", false));
+        let mut deposit = Ledger::new();
+        deposit.mutate(transaction(0, TransactionKind::Deposit)).expect("");
+        deposit.mutate(transaction(0, TransactionKind::Dispute)).expect("");
+        deposit.mutate(transaction(0, TransactionKind::Chargeback)).expect("");
+        assert_eq!(deposit[&0], client(0, "0", "0", true));
+
+        let refuse = LedgerConfig { withdrawal_disputes: WithdrawalDisputes::Refuse, ..Default::default() };
+        assert!(matches!(ledger(refuse.clone(), None), Err(TransactionError::WithdrawalNotDisputable)));
+        // deposits can still be disputed
+        let mut ledger = Ledger::new().with_config(refuse);
+        ledger.mutate(transaction(0, TransactionKind::Deposit)).expect("");
+        ledger.mutate(transaction(0, TransactionKind::Dispute)).expect("");
+        assert_eq!(ledger[&0], client(0, "0", "10", false));
+    }
+
+    #[test]
+    fn retention() {
+        let mut ledger = Ledger::new().with_config(LedgerConfig { retention: Retention::Transactions(2), ..Default::default() });
+        for id in 0..3 {
+            ledger.mutate(Transaction { id, client_id: 0, kind: TransactionKind::Deposit, amount: "10".parse().unwrap(), currency: Currency::default() }).expect("");
+        }
+        ledger.mutate(Transaction { id: 1, client_id: 0, kind: TransactionKind::Dispute, amount: "0".parse().unwrap(), currency: Currency::default() }).expect("");
+        ledger.mutate(Transaction { id: 3, client_id: 0, kind: TransactionKind::Withdrawal, amount: "1".parse().unwrap(), currency: Currency::default() }).expect("");
+        ledger.mutate(Transaction { id: 4, client_id: 0, kind: TransactionKind::Withdrawal, amount: "1".parse().unwrap(), currency: Currency::default() }).expect("");
+
+        // 0 and 1 are past their window, but 1 is kept while disputed
+        assert_eq!(ledger.transactions.len(), 4);
+        assert!(matches!(
+            ledger.mutate(Transaction { id: 0, client_id: 0, kind: TransactionKind::Dispute, amount: "0".parse().unwrap(), currency: Currency::default() }),
+            Err(TransactionError::DisputeWindowExpired)));
+        ledger.mutate(Transaction { id: 1, client_id: 0, kind: TransactionKind::Resolve, amount: "0".parse().unwrap(), currency: Currency::default() }).expect("");
+        ledger.mutate(Transaction { id: 2, client_id: 0, kind: TransactionKind::Dispute, amount: "0".parse().unwrap(), currency: Currency::default() }).expect("");
+        assert!(matches!(
+            ledger.mutate(Transaction { id: 5, client_id: 0, kind: TransactionKind::Dispute, amount: "0".parse().unwrap(), currency: Currency::default() }),
+            Err(TransactionError::ReferencedTransactionNonexistent)));
+
+        // the window survives a snapshot
+        let mut bytes = Vec::new();
+        ledger.snapshot(&mut bytes).unwrap();
+        let mut restored = Ledger::restore(bytes.as_slice()).unwrap().with_config(ledger.config().clone());
+        for id in 5..7 {
+            restored.mutate(Transaction { id, client_id: 0, kind: TransactionKind::Deposit, amount: "1".parse().unwrap(), currency: Currency::default() }).expect("");
+        }
+        assert!(matches!(
+            restored.mutate(Transaction { id: 3, client_id: 0, kind: TransactionKind::Dispute, amount: "0".parse().unwrap(), currency: Currency::default() }),
+            Err(TransactionError::DisputeWindowExpired)));
+
+        // restored with a window in time instead, the transactions in it get a full one from the current clock
+        let mut restored = Ledger::restore(bytes.as_slice()).unwrap();
+        restored.advance(1_000).unwrap();
+        let mut restored = restored.with_config(LedgerConfig { retention: Retention::Time(100), ..Default::default() });
+        restored.mutate(Transaction { id: 5, client_id: 0, kind: TransactionKind::Deposit, amount: "1".parse().unwrap(), currency: Currency::default() }).expect("");
+        restored.mutate(Transaction { id: 3, client_id: 0, kind: TransactionKind::Dispute, amount: "0".parse().unwrap(), currency: Currency::default() }).expect("");
+        restored.advance(1_101).unwrap();
+        assert!(matches!(
+            restored.mutate(Transaction { id: 4, client_id: 0, kind: TransactionKind::Dispute, amount: "0".parse().unwrap(), currency: Currency::default() }),
+            Err(TransactionError::DisputeWindowExpired)));
+        assert!(restored.transactions.get(3).is_some());
+        // and back, counting from the transactions accepted so far
+        restored.mutate(Transaction { id: 6, client_id: 0, kind: TransactionKind::Deposit, amount: "1".parse().unwrap(), currency: Currency::default() }).expect("");
+        let mut restored = restored.with_config(LedgerConfig { retention: Retention::Transactions(1), ..Default::default() });
+        restored.mutate(Transaction { id: 7, client_id: 0, kind: TransactionKind::Deposit, amount: "1".parse().unwrap(), currency: Currency::default() }).expect("");
+        restored.mutate(Transaction { id: 6, client_id: 0, kind: TransactionKind::Dispute, amount: "0".parse().unwrap(), currency: Currency::default() }).expect("");
+        for id in 8..10 {
+            restored.mutate(Transaction { id, client_id: 0, kind: TransactionKind::Deposit, amount: "1".parse().unwrap(), currency: Currency::default() }).expect("");
+        }
+        assert!(matches!(
+            restored.mutate(Transaction { id: 7, client_id: 0, kind: TransactionKind::Dispute, amount: "0".parse().unwrap(), currency: Currency::default() }),
+            Err(TransactionError::DisputeWindowExpired)));
+
+        // a partially disputed deposit is evicted once what's disputed is settled, so the rest can't be disputed anymore
+        let mut ledger = Ledger::new().with_config(LedgerConfig { retention: Retention::Transactions(1), ..Default::default() });
+        ledger.mutate(Transaction { id: 0, client_id: 0, kind: TransactionKind::Deposit, amount: "10".parse().unwrap(), currency: Currency::default() }).expect("");
+        ledger.mutate(Transaction { id: 0, client_id: 0, kind: TransactionKind::Dispute, amount: "2".parse().unwrap(), currency: Currency::default() }).expect("");
+        for id in 1..3 {
+            ledger.mutate(Transaction { id, client_id: 0, kind: TransactionKind::Deposit, amount: "1".parse().unwrap(), currency: Currency::default() }).expect("");
+        }
+        ledger.mutate(Transaction { id: 0, client_id: 0, kind: TransactionKind::Resolve, amount: "2".parse().unwrap(), currency: Currency::default() }).expect("");
+        assert!(ledger.transactions.get(0).is_none());
+        assert!(matches!(
+            ledger.mutate(Transaction { id: 0, client_id: 0, kind: TransactionKind::Dispute, amount: "8".parse().unwrap(), currency: Currency::default() }),
+            Err(TransactionError::DisputeWindowExpired)));
+        assert_eq!(ledger[&0].balance(Default::default()).available(), "12".parse().unwrap());
+
+        // only the evicted ids themselves are off limits, not lower ones
+        let mut ledger = Ledger::new().with_config(LedgerConfig { retention: Retention::Transactions(1), ..Default::default() });
+        for id in [100, 101, 102, 5] {
+            ledger.mutate(Transaction { id, client_id: 0, kind: TransactionKind::Deposit, amount: "1".parse().unwrap(), currency: Currency::default() }).expect("");
+        }
+        assert!(matches!(
+            ledger.mutate(Transaction { id: 100, client_id: 0, kind: TransactionKind::Deposit, amount: "1".parse().unwrap(), currency: Currency::default() }),
+            Err(TransactionError::EvictedTransactionId)));
+        assert!(matches!(
+            ledger.mutate(Transaction { id: 101, client_id: 0, kind: TransactionKind::Dispute, amount: "0".parse().unwrap(), currency: Currency::default() }),
+            Err(TransactionError::DisputeWindowExpired)));
+        assert!(matches!(
+            ledger.mutate(Transaction { id: 4, client_id: 0, kind: TransactionKind::Dispute, amount: "0".parse().unwrap(), currency: Currency::default() }),
+            Err(TransactionError::ReferencedTransactionNonexistent)));
+    }
+
+    #[test]
+    fn snapshot() {
+        let eur: Currency = "EUR".parse().unwrap();
+        let mut ledger = Ledger::new();
+        ledger.mutate(Transaction { id: 0, client_id: 0, kind: TransactionKind::Deposit, amount: "10".parse().unwrap(), currency: eur }).expect("");
+        ledger.mutate(Transaction { id: 1, client_id: 0, kind: TransactionKind::Deposit, amount: "5".parse().unwrap(), currency: Currency::default() }).expect("");
+        ledger.mutate(Transaction { id: 2, client_id: 1, kind: TransactionKind::Deposit, amount: "1.5".parse().unwrap(), currency: Currency::default() }).expect("");
+        ledger.mutate(Transaction { id: 0, client_id: 0, kind: TransactionKind::Dispute, amount: "0".parse().unwrap(), currency: eur }).expect("");
+        ledger.mutate(Transaction { id: 2, client_id: 1, kind: TransactionKind::Dispute, amount: "0".parse().unwrap(), currency: eur }).expect("");
+        ledger.mutate(Transaction { id: 2, client_id: 1, kind: TransactionKind::Chargeback, amount: "0".parse().unwrap(), currency: eur }).expect("");
+        ledger.mutate(Transaction { id: 4, client_id: 0, kind: TransactionKind::Deposit, amount: "4".parse().unwrap(), currency: Currency::default() }).expect("");
+        ledger.mutate(Transaction { id: 4, client_id: 0, kind: TransactionKind::Dispute, amount: "1".parse().unwrap(), currency: Currency::default() }).expect("");
+
+        let mut bytes = Vec::new();
+        ledger.snapshot(&mut bytes).unwrap();
+        let mut restored = Ledger::restore(bytes.as_slice()).unwrap();
+        assert_eq!(*restored, *ledger);
+        assert_eq!(restored.partial_disputes, ledger.partial_disputes);
+        assert_eq!(restored.audit_trail(), ledger.audit_trail());
+        for transaction in ledger.transactions.iter() {
+            assert_eq!(restored.transactions.get(transaction.transaction().id), Some(transaction));
+        }
+
+        // dispute state and ids survive
+        restored.mutate(Transaction { id: 0, client_id: 0, kind: TransactionKind::Resolve, amount: "0".parse().unwrap(), currency: eur }).expect("");
+        assert!(matches!(restored.mutate(Transaction { id: 1, client_id: 0, kind: TransactionKind::Deposit, amount: "6".parse().unwrap(), currency: Currency::default() }), Err(TransactionError::DuplicateTransaction)));
+        assert!(matches!(restored.mutate(Transaction { id: 3, client_id: 1, kind: TransactionKind::Deposit, amount: "1".parse().unwrap(), currency: Currency::default() }), Err(TransactionError::ClientLocked)));
+
+        let newer = String::from_utf8(bytes).unwrap().replacen("\"version\":1", "\"version\":2", 1);
+        assert!(matches!(Ledger::restore(newer.as_bytes()), Err(SnapshotError::UnsupportedVersion(2))));
+        assert!(matches!(Ledger::restore("{}".as_bytes()), Err(SnapshotError::Format(_))));
+    }
+}
@@ -0,0 +1,26 @@
+//! Transaction engine: applies deposits, withdrawals and disputes to client accounts.
+//!
+//! `Ledger` holds the clients and the transactions they can dispute; feed it `Transaction`s via `Ledger::mutate`.
+//! Monetary values are `Amount`s, a fixed point type with 4 fraction digits, optionally tagged with a `Currency`.
+//! `ConcurrentLedger` is its thread-safe, sharded counterpart, `EventSourcedLedger` keeps the full history for queries.
+//! `Journal` persists accepted transactions to survive a crash.
+//! The `api` module reads transactions from, and writes client balances to CSV.
+
+pub mod amount;
+pub mod api;
+pub mod checkpoint;
+pub mod concurrent;
+pub mod disk;
+pub mod events;
+pub mod journal;
+pub mod ledger;
+pub mod money;
+pub mod policy;
+pub mod store;
+pub mod timeline;
+
+pub use amount::Amount;
+pub use events::{Event, EventSourcedLedger};
+pub use journal::Journal;
+pub use ledger::{AccountStatus, AuditEntry, Balance, Client, Ledger, LedgerConfig, LockMode, Retention, Transaction, TransactionError, TransactionKind, TransactionState, WithdrawalDisputes};
+pub use money::{Currency, Money};
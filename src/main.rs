@@ -1,224 +1,402 @@
-extern crate core;
-
-use std::{env, io};
-use std::num::ParseIntError;
-use std::path::Path;
+use std::{env, fs, io};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
 use std::process::exit;
+use std::sync::Arc;
 
 use csv::{Reader, ReaderBuilder, Trim, Writer, WriterBuilder};
-use serde::{Deserialize, Serialize};
-
-use crate::amount::Amount;
-use crate::ledger::{Client, Ledger, Transaction, TransactionKind, TransactionKindConversionError};
 
-mod amount;
-mod ledger;
+use rust_coding_test::api::{apply_transactions, Error, process_transactions_parallel, Recorders, write_audit, write_clients};
+use rust_coding_test::checkpoint::Checkpointer;
+use rust_coding_test::disk::DiskStore;
+use rust_coding_test::journal::FsyncPolicy;
+use rust_coding_test::policy::{AllowNegativeOnDispute, LedgerPolicy, Overdraft, Strict};
+use rust_coding_test::store::TransactionStore;
+use rust_coding_test::{Amount, Journal, Ledger, LedgerConfig, LockMode, Retention, WithdrawalDisputes};
 
 #[derive(Debug)]
-enum Error {
-    Read(csv::Error),
-    Write(io::Error),
-    Parse(String),
+struct Args {
+    input: String,
+    rejects: Option<String>,
+    /// where to write the status changes of clients
+    audit: Option<String>,
+    strict: bool,
+    /// worker threads; 0 or 1 processes on the main thread
+    workers: usize,
+    journal: Option<String>,
+    fsync: FsyncPolicy,
+    /// ledger to start from
+    snapshot: Option<String>,
+    /// where to save the final ledger
+    save_snapshot: Option<String>,
+    checkpoint: Option<String>,
+    /// records between checkpoints
+    checkpoint_every: u64,
+    /// continue from `checkpoint`, if there is one
+    resume: bool,
+    /// directory to spill stored transactions to
+    spill: Option<String>,
+    /// transactions kept in memory before spilling
+    spill_every: usize,
+    config: LedgerConfig,
 }
 
-impl From<csv::Error> for Error {
-    fn from(error: csv::Error) -> Self {
-        Error::Read(error)
+impl Default for Args {
+    fn default() -> Self {
+        Args {
+            input: String::new(),
+            rejects: None,
+            audit: None,
+            strict: false,
+            workers: 0,
+            journal: None,
+            fsync: FsyncPolicy::default(),
+            snapshot: None,
+            save_snapshot: None,
+            checkpoint: None,
+            checkpoint_every: 1_000_000,
+            resume: false,
+            spill: None,
+            spill_every: 1_000_000,
+            config: LedgerConfig::default(),
+        }
     }
 }
 
-impl From<io::Error> for Error {
-    fn from(error: io::Error) -> Self {
-        Error::Write(error)
+fn parse_args(mut args: impl Iterator<Item=String>) -> Result<Args, String> {
+    let mut parsed = Args::default();
+    let mut input = None;
+    // the policy is built last, as per-client overdraft limits add to it
+    let mut policy = None;
+    let mut overdraft_limits = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--rejects" => { parsed.rejects = Some(args.next().ok_or("--rejects requires a file name")?) }
+            "--audit" => { parsed.audit = Some(args.next().ok_or("--audit requires a file name")?) }
+            "--strict" => { parsed.strict = true }
+            "--workers" => {
+                let workers = args.next().ok_or("--workers requires a number")?;
+                parsed.workers = workers.parse().map_err(|_| format!("invalid number of workers: {}", workers))?;
+            }
+            "--journal" => { parsed.journal = Some(args.next().ok_or("--journal requires a file name")?) }
+            "--fsync" => {
+                parsed.fsync = match args.next().ok_or("--fsync requires a policy")?.as_str() {
+                    "always" => { FsyncPolicy::Always }
+                    "never" => { FsyncPolicy::Never }
+                    n => { FsyncPolicy::Every(n.parse().ok().filter(|&n| n > 0).ok_or(format!("invalid fsync policy: {}", n))?) }
+                }
+            }
+            "--snapshot" => { parsed.snapshot = Some(args.next().ok_or("--snapshot requires a file name")?) }
+            "--save-snapshot" => { parsed.save_snapshot = Some(args.next().ok_or("--save-snapshot requires a file name")?) }
+            "--checkpoint" => { parsed.checkpoint = Some(args.next().ok_or("--checkpoint requires a file name")?) }
+            "--checkpoint-every" => {
+                let every = args.next().ok_or("--checkpoint-every requires a number")?;
+                parsed.checkpoint_every = every.parse().ok().filter(|&n| n > 0).ok_or(format!("invalid checkpoint interval: {}", every))?;
+            }
+            "--resume" => { parsed.resume = true }
+            "--spill" => { parsed.spill = Some(args.next().ok_or("--spill requires a directory")?) }
+            "--spill-every" => {
+                let every = args.next().ok_or("--spill-every requires a number")?;
+                parsed.spill_every = every.parse().ok().filter(|&n| n > 0).ok_or(format!("invalid spill interval: {}", every))?;
+            }
+            "--dispute-window" => { parsed.config.retention = parse_retention(&args.next().ok_or("--dispute-window requires a length")?)? }
+            "--withdrawal-disputes" => {
+                parsed.config.withdrawal_disputes = match args.next().ok_or("--withdrawal-disputes requires a policy")?.as_str() {
+                    "refuse" => { WithdrawalDisputes::Refuse }
+                    "refund" => { WithdrawalDisputes::Refund }
+                    policy => { return Err(format!("invalid withdrawal dispute policy: {}", policy)); }
+                }
+            }
+            "--policy" => { policy = Some(args.next().ok_or("--policy requires a policy")?) }
+            "--overdraft-limits" => { overdraft_limits = Some(args.next().ok_or("--overdraft-limits requires a file name")?) }
+            "--chargeback-lock" => { parsed.config.chargeback_lock = parse_lock_mode(&args.next().ok_or("--chargeback-lock requires a mode")?)? }
+            "--freeze-lock" => { parsed.config.freeze_lock = parse_lock_mode(&args.next().ok_or("--freeze-lock requires a mode")?)? }
+            _ if arg.starts_with("--") => { return Err(format!("unknown option: {}", arg)); }
+            _ => { input = Some(arg) }
+        }
     }
-}
-
-// FIXME: propagate error details from these 3 different error types
-impl From<TransactionKindConversionError> for Error {
-    fn from(_error: TransactionKindConversionError) -> Self {
-        Error::Parse("enum does not exist".to_string())
+    parsed.input = input.ok_or("specify input file name")?;
+    match (policy, overdraft_limits) {
+        (Some(policy), limits) => { parsed.config.policy = parse_policy(&policy, limits.as_deref())? }
+        (None, Some(_)) => { return Err("--overdraft-limits requires --policy overdraft:<limit>".to_string()); }
+        (None, None) => {}
     }
-}
-
-impl From<ParseIntError> for Error {
-    fn from(_error: ParseIntError) -> Self {
-        Error::Parse("int conversion failed".to_string())
+    if parsed.workers > 1 && (parsed.journal.is_some() || parsed.snapshot.is_some() || parsed.save_snapshot.is_some() || parsed.checkpoint.is_some()) {
+        return Err("--journal, snapshots and checkpoints can't be combined with --workers".to_string());
+    }
+    if parsed.workers > 1 && parsed.audit.is_some() { return Err("--audit can't be combined with --workers".to_string()); }
+    if parsed.journal.is_some() && parsed.checkpoint.is_some() { return Err("--journal can't be combined with --checkpoint".to_string()); }
+    if parsed.resume && parsed.checkpoint.is_none() { return Err("--resume requires --checkpoint".to_string()); }
+    if parsed.spill.is_some() && (parsed.workers > 1 || parsed.snapshot.is_some() || parsed.resume) {
+        return Err("--spill can't be combined with --workers, --snapshot or --resume".to_string());
+    }
+    // shards would count their dispute windows on their own
+    if parsed.config.retention != Retention::Forever && parsed.workers > 1 { return Err("--dispute-window can't be combined with --workers".to_string()); }
+    // the journal doesn't keep timestamps, so replayed transactions would lose their dispute window
+    if matches!(parsed.config.retention, Retention::Time(_)) && parsed.journal.is_some() {
+        return Err("--dispute-window in seconds or days can't be combined with --journal".to_string());
     }
+    Ok(parsed)
 }
 
-impl From<amount::Error> for Error {
-    fn from(error: amount::Error) -> Self {
-        Error::Parse(format!("amount conversion failed: {:?}", error))
+/// `<n>` transactions, `<n>s` seconds or `<n>d` days
+fn parse_retention(window: &str) -> Result<Retention, String> {
+    let invalid = || format!("invalid dispute window: {}", window);
+    let (n, unit) = match window.as_bytes().last() {
+        Some(b's') => { (&window[..window.len() - 1], Some(1)) }
+        Some(b'd') => { (&window[..window.len() - 1], Some(86_400)) }
+        _ => { (window, None) }
+    };
+    let n: u64 = n.parse().map_err(|_| invalid())?;
+    match unit {
+        Some(unit) => { Ok(Retention::Time(n.checked_mul(unit).ok_or_else(invalid)?)) }
+        None => { Ok(Retention::Transactions(n)) }
     }
 }
 
-#[derive(Debug, Deserialize)]
-struct ApiTransaction {
-    #[serde(rename(serialize = "type", deserialize = "type"))]
-    kind: TransactionKind,
-    client: u16,
-    tx: u64,
-    amount: Amount,
+/// `debits`, `everything` or `disputes-only`
+fn parse_lock_mode(mode: &str) -> Result<LockMode, String> {
+    LockMode::from_name(mode).ok_or(format!("invalid lock mode: {}", mode))
 }
 
-impl From<&ApiTransaction> for Transaction {
-    fn from(transaction: &ApiTransaction) -> Self {
-        Transaction::new(transaction.tx, transaction.client, transaction.kind, transaction.amount)
+/// `strict`, `allow-negative-on-dispute` or `overdraft:<limit>`, the latter with the per-client limits read from `limits`, if any
+fn parse_policy(policy: &str, limits: Option<&str>) -> Result<Arc<dyn LedgerPolicy>, String> {
+    match policy.split_once(':') {
+        Some(("overdraft", limit)) => {
+            let overdraft = Overdraft::new(parse_overdraft_limit(limit)?);
+            Ok(Arc::new(match limits {
+                Some(path) => { read_overdraft_limits(overdraft, path)? }
+                None => { overdraft }
+            }))
+        }
+        _ if limits.is_some() => { Err("--overdraft-limits requires --policy overdraft:<limit>".to_string()) }
+        None if policy == "strict" => { Ok(Arc::new(Strict)) }
+        None if policy == "allow-negative-on-dispute" => { Ok(Arc::new(AllowNegativeOnDispute)) }
+        _ => { Err(format!("invalid policy: {}", policy)) }
     }
 }
 
-#[derive(Debug, Serialize)]
-struct ApiClient {
-    client: u16,
-    available: Amount,
-    held: Amount,
-    total: Amount,
-    locked: bool,
+fn parse_overdraft_limit(limit: &str) -> Result<Amount, String> {
+    limit.parse().ok().filter(|&limit| limit >= Amount::default()).ok_or(format!("invalid overdraft limit: {}", limit))
 }
 
-impl From<&Client> for ApiClient {
-    fn from(client: &Client) -> Self {
-        ApiClient {
-            client: client.id(),
-            available: client.available(),
-            held: client.held(),
-            total: client.total(),
-            locked: client.locked(),
-        }
+/// adds the limits of a CSV file with a `client, limit` header to `overdraft`
+fn read_overdraft_limits(mut overdraft: Overdraft, path: &str) -> Result<Overdraft, String> {
+    let mut reader = ReaderBuilder::new().trim(Trim::All).from_path(path).map_err(|err| format!("can't read {}: {}", path, err))?;
+    for record in reader.records() {
+        let record = record.map_err(|err| format!("can't read {}: {}", path, err))?;
+        let line = record.position().map(|p| p.line()).unwrap_or_default();
+        let (Some(client_id), Some(limit)) = (record.get(0), record.get(1)) else {
+            return Err(format!("invalid overdraft limit on line {} of {}", line, path));
+        };
+        let client_id = client_id.parse().map_err(|_| format!("invalid client on line {} of {}: {}", line, path, client_id))?;
+        overdraft = overdraft.with_limit(client_id, parse_overdraft_limit(limit)?);
     }
+    Ok(overdraft)
 }
 
-fn run<P: AsRef<Path>>(path: P) -> Result<String, Error> {
+fn report_malformed(line: u64, error: &Error) {
+    eprintln!("skipping line {}: {}", line, error);
+}
+
+/// returns the number of skipped malformed lines
+fn run(args: &Args) -> Result<u64, Error> {
     let mut reader = ReaderBuilder::new()
         .trim(Trim::All)
         .flexible(true)
-        .from_path(path)?;
+        .from_path(&args.input)?;
 
     let mut wtr = WriterBuilder::new()
         .from_writer(io::stdout());
 
-    process_transactions(&mut reader, &mut wtr)?;
-
-    Ok("ok".to_string())
-}
-
-fn process_transactions<R: io::Read, W: io::Write>(reader: &mut Reader<R>, wtr: &mut Writer<W>) -> Result<String, Error> {
-    let mut ledger = Ledger::new();
-
-    // FIXME: csv+serde has a bug in `flexible` support: https://github.com/BurntSushi/rust-csv/issues/145 ; for the time being, we'll manually deserialize
-    // for result in reader.deserialize() {
-    //     let transaction: ApiTransaction = result?;
-    for result in reader.records() {
-        let record = result?;
-        let transaction = ApiTransaction {
-            kind: record.get(0).ok_or(Error::Parse("kind missing".to_string()))?.parse()?,
-            client: record.get(1).ok_or(Error::Parse("client missing".to_string()))?.parse()?,
-            tx: record.get(2).ok_or(Error::Parse("tx missing".to_string()))?.parse()?,
-            amount: record.get(3).unwrap_or("0").parse()?,
-        };
+    if args.workers > 1 {
+        let mut rejects = args.rejects.as_ref().map(File::create).transpose()?.map(Writer::from_writer);
+        return process_transactions_parallel(&mut reader, &mut wtr, rejects.as_mut(), Some(&mut report_malformed), args.strict, args.workers, args.config.clone());
+    }
 
-        // println!("{:?}", transaction);
-        // FIXME: add logging
-        let _result = ledger.mutate((&transaction).into());
+    let mut checkpoints = args.checkpoint.as_ref().map(|path| {
+        let checkpoints = Checkpointer::new(path, args.checkpoint_every);
+        match &args.rejects {
+            Some(rejects) => { checkpoints.with_rejects(rejects) }
+            None => { checkpoints }
+        }
+    });
+    let checkpoint = checkpoints.as_mut().filter(|_| args.resume).map(Checkpointer::resume).transpose()?.flatten();
+    // on resume, the rejects report continues from the checkpoint, its header already written if it has anything
+    let mut rejects = match (&args.rejects, &checkpoint) {
+        (Some(path), Some(checkpoint)) => { Some(WriterBuilder::new().has_headers(checkpoint.rejects == 0).from_writer(checkpoint.reopen_rejects(path)?)) }
+        (Some(path), None) => { Some(Writer::from_writer(File::create(path)?)) }
+        (None, _) => { None }
+    };
+
+    if let Some(dir) = &args.spill {
+        let ledger = Ledger::with_store(DiskStore::new(dir, args.spill_every)?).with_config(args.config.clone());
+        return process(args, ledger, 0, &mut reader, &mut wtr, rejects.as_mut(), checkpoints);
     }
 
-    for x in ledger.iter() {
-        let client: ApiClient = x.1.into();
-        wtr.serialize(client)?;
+    let mut ledger = match &args.snapshot {
+        Some(path) => { Ledger::restore(BufReader::new(File::open(path)?))? }
+        None => { Ledger::new() }
+    };
+    let mut skipped = 0;
+    if let Some(checkpoint) = checkpoint {
+        reader.seek(checkpoint.position)?;
+        ledger = checkpoint.ledger;
+        skipped = checkpoint.skipped;
     }
-    wtr.flush()?;
+    process(args, ledger.with_config(args.config.clone()), skipped, &mut reader, &mut wtr, rejects.as_mut(), checkpoints)
+}
 
-    Ok("ok".to_string())
+/// applies the rest of the input to `ledger` and writes it out
+fn process<S: TransactionStore, R: io::Read, O: io::Write, E: io::Write>(args: &Args, mut ledger: Ledger<S>, mut skipped: u64, reader: &mut Reader<R>,
+                                                                          wtr: &mut Writer<O>, rejects: Option<&mut Writer<E>>, mut checkpoints: Option<Checkpointer>) -> Result<u64, Error> {
+    // continues a crashed run, if any
+    let mut journal = args.journal.as_ref().map(|path| Journal::open(path, args.fsync, &mut ledger)).transpose()?;
+    let recorders = Recorders { journal: journal.as_mut(), checkpoints: checkpoints.as_mut(), malformed: Some(&mut report_malformed), ..Default::default() };
+    skipped += apply_transactions(&mut ledger, reader, rejects, args.strict, recorders)?;
+
+    if let Some(path) = &args.save_snapshot {
+        // write-then-rename, so a crash can't leave a half-written snapshot behind
+        let temp_path = format!("{}.tmp", path);
+        let mut writer = BufWriter::new(File::create(&temp_path)?);
+        ledger.snapshot(&mut writer)?;
+        writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&temp_path, path)?;
+    }
+    write_clients(&ledger, wtr)?;
+    if let Some(path) = &args.audit {
+        write_audit(&ledger, &mut Writer::from_path(path)?)?;
+    }
+    if let Some(journal) = journal {
+        journal.finish()?;
+    }
+    if let Some(checkpoints) = checkpoints {
+        checkpoints.finish()?;
+    }
+    Ok(skipped)
 }
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() <= 1 {
-        eprintln!("specify input file name");
-        exit(1);
-    }
+    let args = match parse_args(env::args().skip(1)) {
+        Ok(args) => { args }
+        Err(err) => {
+            eprintln!("{}", err);
+            eprintln!("usage: rust-coding-test [--strict] [--rejects <file>] [--audit <file>] [--workers <n>] [--journal <file> [--fsync always|never|<n>]]");
+            eprintln!("         [--snapshot <file>] [--save-snapshot <file>] [--checkpoint <file> [--checkpoint-every <n>] [--resume]]");
+            eprintln!("         [--spill <dir> [--spill-every <n>]] [--dispute-window <n>[s|d]] [--withdrawal-disputes refuse|refund]");
+            eprintln!("         [--policy strict|allow-negative-on-dispute|overdraft:<limit> [--overdraft-limits <file>]]");
+            eprintln!("         [--chargeback-lock <mode>] [--freeze-lock <mode>] <input file>");
+            exit(1);
+        }
+    };
 
-    match run(&args[1]) {
-        Ok(_res) => {}
+    match run(&args) {
+        Ok(0) => {}
+        Ok(skipped) => {
+            eprintln!("skipped {} malformed line(s)", skipped);
+            exit(2);
+        }
         Err(err) => {
-            println!("{:?}", err)
+            eprintln!("{}", err);
+            exit(1);
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use csv::{ReaderBuilder, Trim, WriterBuilder};
-
-    use crate::process_transactions;
-
-    pub fn assert_transaction(data: &str, result: &str) {
-        let mut rdr = ReaderBuilder::new()
-            .trim(Trim::All)
-            .flexible(true)
-            .has_headers(false)
-            .from_reader(data.as_bytes());
-
-        let mut wrt = WriterBuilder::new()
-            .has_headers(false)
-            .flexible(true)
-            .from_writer(Vec::new());
-
-        process_transactions(&mut rdr, &mut wrt).unwrap();
-        let bytes = wrt.into_inner().unwrap();
-
-        // for testing purposes: sort lines to keep result stable
-        let result_string = String::from_utf8(bytes).unwrap();
-        let mut lines: Vec<&str> = result_string.trim().split('\n').collect();
-        lines.sort();
-        let sorted_lines = lines.join("\n");
-
-        assert_eq!(sorted_lines, result.to_string())
-    }
-
-    #[test]
-    pub fn basic() {
-        assert_transaction(
-            concat!(
-            "deposit, 1, 1, 1.0\n",
-            "deposit, 2, 2, 2.0\n",
-            "deposit, 1, 3, 2.0\n",
-            "withdrawal, 1, 4, 1.5\n",
-            "withdrawal, 2, 5, 3.0\n"),
-            concat!(
-            "1,1.5,0,1.5,false\n",
-            "2,2,0,2,false"
-            ),
-        )
-    }
+    use std::{env, fs, process};
 
-    #[test]
-    pub fn tx_reference_fail() {
-        assert_transaction("deposit, 1, 1, 10\ndispute, 1, 2", "1,10,0,10,false");
+    use rust_coding_test::journal::FsyncPolicy;
+    use rust_coding_test::{Ledger, LedgerConfig, LockMode, Retention, Transaction, TransactionKind, WithdrawalDisputes};
 
-        assert_transaction("deposit, 1, 1, 10\nresolve, 1, 2", "1,10,0,10,false");
+    use crate::parse_args;
 
-        assert_transaction("deposit, 1, 1, 10\ndispute,1,1\nresolve,1,2", "1,0,10,10,false");
+    /// whether `client_id` can withdraw 40 out of the 6 left of a deposit of 10, and then dispute the deposit
+    fn overdraws(config: LedgerConfig, client_id: u16) -> (bool, bool) {
+        let mut ledger = Ledger::new().with_config(config);
+        let transaction = |id, kind, amount: &str| Transaction::new(id, client_id, kind, amount.parse().unwrap(), Default::default());
+        ledger.mutate(transaction(1, TransactionKind::Deposit, "10")).unwrap();
+        ledger.mutate(transaction(2, TransactionKind::Withdrawal, "4")).unwrap();
+        let withdrawal = ledger.mutate(transaction(3, TransactionKind::Withdrawal, "40")).is_ok();
+        (withdrawal, ledger.mutate(transaction(1, TransactionKind::Dispute, "0")).is_ok())
     }
 
     #[test]
-    pub fn dispute() {
-        assert_transaction("deposit, 1, 1, 10\ndispute, 1, 1, 2.5\n", "1,0,10,10,false");
+    pub fn args() {
+        let args = parse_args(["--rejects", "rejects.csv", "input.csv", "--strict"].iter().map(|s| s.to_string())).unwrap();
+        assert_eq!(args.input, "input.csv");
+        assert_eq!(args.rejects.as_deref(), Some("rejects.csv"));
+        assert!(args.strict);
+        assert_eq!(args.workers, 0);
+
+        assert_eq!(parse_args(["--workers", "4", "input.csv"].iter().map(|s| s.to_string())).unwrap().workers, 4);
+        assert!(parse_args(["--workers", "many", "input.csv"].iter().map(|s| s.to_string())).is_err());
+
+        let args = parse_args(["--journal", "journal.bin", "--fsync", "100", "input.csv"].iter().map(|s| s.to_string())).unwrap();
+        assert_eq!(args.journal.as_deref(), Some("journal.bin"));
+        assert_eq!(args.fsync, FsyncPolicy::Every(100));
+        assert_eq!(parse_args(["--fsync", "always", "input.csv"].iter().map(|s| s.to_string())).unwrap().fsync, FsyncPolicy::Always);
+        assert!(parse_args(["--fsync", "0", "input.csv"].iter().map(|s| s.to_string())).is_err());
+        assert!(parse_args(["--journal", "journal.bin", "--workers", "4", "input.csv"].iter().map(|s| s.to_string())).is_err());
+
+        let args = parse_args(["--snapshot", "monday.json", "--save-snapshot", "tuesday.json", "input.csv"].iter().map(|s| s.to_string())).unwrap();
+        assert_eq!(args.snapshot.as_deref(), Some("monday.json"));
+        assert_eq!(args.save_snapshot.as_deref(), Some("tuesday.json"));
+        assert!(parse_args(["--snapshot", "monday.json", "--workers", "4", "input.csv"].iter().map(|s| s.to_string())).is_err());
+
+        let args = parse_args(["--checkpoint", "run.ckpt", "--checkpoint-every", "500", "--resume", "input.csv"].iter().map(|s| s.to_string())).unwrap();
+        assert_eq!(args.checkpoint.as_deref(), Some("run.ckpt"));
+        assert_eq!(args.checkpoint_every, 500);
+        assert!(args.resume);
+        assert!(parse_args(["--resume", "input.csv"].iter().map(|s| s.to_string())).is_err());
+        assert!(parse_args(["--checkpoint", "run.ckpt", "--journal", "journal.bin", "input.csv"].iter().map(|s| s.to_string())).is_err());
+
+        let args = parse_args(["--spill", "spill", "--spill-every", "1000", "--journal", "journal.bin", "input.csv"].iter().map(|s| s.to_string())).unwrap();
+        assert_eq!(args.spill.as_deref(), Some("spill"));
+        assert_eq!(args.spill_every, 1000);
+        assert!(parse_args(["--spill", "spill", "--snapshot", "monday.json", "input.csv"].iter().map(|s| s.to_string())).is_err());
+        assert!(parse_args(["--spill-every", "0", "input.csv"].iter().map(|s| s.to_string())).is_err());
+
+        assert_eq!(parse_args(["--dispute-window", "1000", "input.csv"].iter().map(|s| s.to_string())).unwrap().config.retention, Retention::Transactions(1000));
+        assert_eq!(parse_args(["--dispute-window", "120d", "input.csv"].iter().map(|s| s.to_string())).unwrap().config.retention, Retention::Time(120 * 86_400));
+        assert_eq!(parse_args(["--dispute-window", "60s", "input.csv"].iter().map(|s| s.to_string())).unwrap().config.retention, Retention::Time(60));
+        assert!(parse_args(["--dispute-window", "d", "input.csv"].iter().map(|s| s.to_string())).is_err());
+        assert!(parse_args(["--dispute-window", "120d", "--journal", "journal.bin", "input.csv"].iter().map(|s| s.to_string())).is_err());
+        assert_eq!(parse_args(["--withdrawal-disputes", "refuse", "input.csv"].iter().map(|s| s.to_string())).unwrap().config.withdrawal_disputes, WithdrawalDisputes::Refuse);
+        assert_eq!(parse_args(["--withdrawal-disputes", "refuse", "--workers", "4", "input.csv"].iter().map(|s| s.to_string())).unwrap().config.withdrawal_disputes, WithdrawalDisputes::Refuse);
+        assert!(parse_args(["--dispute-window", "1000", "--workers", "4", "input.csv"].iter().map(|s| s.to_string())).is_err());
+        assert!(parse_args(["--withdrawal-disputes", "ignore", "input.csv"].iter().map(|s| s.to_string())).is_err());
+        let args = parse_args(["--policy", "overdraft:40", "input.csv"].iter().map(|s| s.to_string())).unwrap();
+        assert_eq!(overdraws(args.config, 1), (true, false));
+        assert_eq!(overdraws(parse_args(["--policy", "strict", "input.csv"].iter().map(|s| s.to_string())).unwrap().config, 1), (false, false));
+        assert_eq!(overdraws(parse_args(["input.csv"].iter().map(|s| s.to_string())).unwrap().config, 1), (false, true));
+        assert!(parse_args(["--policy", "overdraft:-1", "input.csv"].iter().map(|s| s.to_string())).is_err());
+
+        let limits = env::temp_dir().join(format!("overdraft-limits-{}-main", process::id()));
+        fs::write(&limits, "client, limit\n1, 100\n2, 0\n").unwrap();
+        let limits = limits.to_str().unwrap();
+        let args = parse_args(["--overdraft-limits", limits, "--policy", "overdraft:40", "input.csv"].iter().map(|s| s.to_string())).unwrap();
+        assert_eq!(overdraws(args.config.clone(), 1), (true, true));
+        assert_eq!(overdraws(args.config.clone(), 2), (false, false));
+        assert_eq!(overdraws(args.config, 3), (true, false));
+        assert!(parse_args(["--overdraft-limits", limits, "input.csv"].iter().map(|s| s.to_string())).is_err());
+        assert!(parse_args(["--overdraft-limits", limits, "--policy", "strict", "input.csv"].iter().map(|s| s.to_string())).is_err());
+        fs::write(limits, "client, limit\n1, -5\n").unwrap();
+        assert!(parse_args(["--overdraft-limits", limits, "--policy", "overdraft:40", "input.csv"].iter().map(|s| s.to_string())).is_err());
+        fs::remove_file(limits).unwrap();
+        assert!(parse_args(["--overdraft-limits", limits, "--policy", "overdraft:40", "input.csv"].iter().map(|s| s.to_string())).is_err());
+        assert!(parse_args(["--policy", "lenient", "input.csv"].iter().map(|s| s.to_string())).is_err());
+        assert_eq!(overdraws(parse_args(["--policy", "strict", "--workers", "4", "input.csv"].iter().map(|s| s.to_string())).unwrap().config, 1), (false, false));
+
+        let args = parse_args(["--chargeback-lock", "debits", "--freeze-lock", "disputes-only", "input.csv"].iter().map(|s| s.to_string())).unwrap();
+        assert_eq!((args.config.chargeback_lock, args.config.freeze_lock), (LockMode::Debits, LockMode::DisputesOnly));
+        assert!(parse_args(["--freeze-lock", "credits", "input.csv"].iter().map(|s| s.to_string())).is_err());
+        assert_eq!(parse_args(["--chargeback-lock", "debits", "--workers", "4", "input.csv"].iter().map(|s| s.to_string())).unwrap().config.chargeback_lock, LockMode::Debits);
+        assert_eq!(parse_args(["--audit", "audit.csv", "input.csv"].iter().map(|s| s.to_string())).unwrap().audit.as_deref(), Some("audit.csv"));
+        assert!(parse_args(["--audit", "audit.csv", "--workers", "4", "input.csv"].iter().map(|s| s.to_string())).is_err());
+        assert!(parse_args(["--rejects"].iter().map(|s| s.to_string())).is_err());
+        assert!(parse_args(["--unknown", "input.csv"].iter().map(|s| s.to_string())).is_err());
+        assert!(parse_args([].iter().map(|s: &&str| s.to_string())).is_err());
     }
-
-    #[test]
-    pub fn resolve() {
-        assert_transaction("deposit, 1, 1, 10\ndispute, 1, 1, 2.5\nresolve,1,1", "1,10,0,10,false");
-    }
-
-    #[test]
-    pub fn chargeback() {
-        assert_transaction("deposit, 1, 1, 10\ndispute, 1, 1, 2.5\nchargeback,1,1,2.5", "1,0,0,0,true");
-    }
-
-    #[test]
-    pub fn locked_user() {
-        assert_transaction("deposit, 1, 1, 10\ndispute, 1, 1, 2.5\nchargeback,1,1,2.5\ndeposit,1,2,5", "1,0,0,0,true");
-    }
-
-}
\ No newline at end of file
+}
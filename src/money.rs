@@ -0,0 +1,188 @@
+use std::fmt;
+use std::fmt::Formatter;
+use std::str::FromStr;
+
+use lazy_static::lazy_static;
+use serde::{de, Deserializer, Serializer};
+use serde::{Deserialize, Serialize};
+
+use crate::amount;
+use crate::amount::{Amount, RoundingMode};
+use crate::money::Error::{CurrencyMismatch, PrecisionTooHigh, UnknownCurrency};
+
+// ISO 4217 codes with their minor units; XXX ("no currency") is used for input without a currency, at full precision.
+// Only the currencies we deal with, and only up to the 4 fraction digits `Amount` can hold
+const CURRENCIES: &[(&str, u32)] = &[
+    ("AED", 2), ("AUD", 2), ("BHD", 3), ("BRL", 2), ("CAD", 2), ("CHF", 2), ("CLF", 4), ("CNY", 2),
+    ("CZK", 2), ("DKK", 2), ("EUR", 2), ("GBP", 2), ("HKD", 2), ("HUF", 2), ("IDR", 2), ("ILS", 2),
+    ("INR", 2), ("ISK", 0), ("JOD", 3), ("JPY", 0), ("KRW", 0), ("KWD", 3), ("MXN", 2), ("NOK", 2),
+    ("NZD", 2), ("OMR", 3), ("PLN", 2), ("RON", 2), ("SEK", 2), ("SGD", 2), ("THB", 2), ("TND", 3),
+    ("TRY", 2), ("USD", 2), ("UYW", 4), ("VND", 0), ("XXX", 4), ("ZAR", 2),
+];
+
+/// ISO 4217 currency, identified by its alphabetic code
+#[derive(Debug, Clone, Copy, Ord, PartialOrd, Eq, PartialEq, Hash)]
+pub struct Currency {
+    index: u8,
+}
+
+lazy_static! {
+    pub static ref NO_CURRENCY: Currency = "XXX".parse().unwrap();
+}
+
+impl Default for Currency {
+    fn default() -> Self { *NO_CURRENCY }
+}
+
+impl Currency {
+    pub fn code(&self) -> &'static str { CURRENCIES[self.index as usize].0 }
+    /// number of fraction digits of the minor unit, e.g. 2 for EUR cents
+    pub fn minor_units(&self) -> u32 { CURRENCIES[self.index as usize].1 }
+}
+
+/// an `Amount` in a given `Currency`; arithmetic refuses to mix currencies
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Money {
+    amount: Amount,
+    currency: Currency,
+}
+
+impl Money {
+    /// fails if `amount` is more precise than the minor unit of `currency`
+    pub fn new(amount: Amount, currency: Currency) -> Result<Money, Error> {
+        if amount.precision() > currency.minor_units() { return Err(PrecisionTooHigh(amount, currency)); }
+        Ok(Money { amount, currency })
+    }
+
+    pub fn amount(&self) -> Amount { self.amount }
+    pub fn currency(&self) -> Currency { self.currency }
+
+    pub fn checked_add(self, rhs: Self) -> Result<Self, Error> {
+        self.check_currency(rhs)?;
+        Ok(Money { amount: self.amount.checked_add(rhs.amount)?, ..self })
+    }
+
+    pub fn checked_sub(self, rhs: Self) -> Result<Self, Error> {
+        self.check_currency(rhs)?;
+        Ok(Money { amount: self.amount.checked_sub(rhs.amount)?, ..self })
+    }
+
+    /// result is rounded to the minor unit of the currency
+    pub fn checked_mul(self, factor: Amount, mode: RoundingMode) -> Result<Self, Error> {
+        let amount = self.amount.checked_mul(factor)?.round(self.currency.minor_units(), mode)?;
+        Ok(Money { amount, ..self })
+    }
+
+    fn check_currency(self, rhs: Self) -> Result<(), Error> {
+        if self.currency != rhs.currency { return Err(CurrencyMismatch(self.currency, rhs.currency)); }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    UnknownCurrency(String),
+    CurrencyMismatch(Currency, Currency),
+    PrecisionTooHigh(Amount, Currency),
+    Amount(amount::Error),
+}
+
+impl From<amount::Error> for Error {
+    fn from(error: amount::Error) -> Self {
+        Error::Amount(error)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            UnknownCurrency(s) => { write!(f, "unknown currency: '{}'", s) }
+            CurrencyMismatch(a, b) => { write!(f, "currency mismatch: {} vs {}", a, b) }
+            PrecisionTooHigh(a, c) => { write!(f, "precision too high for {}: '{}'", c, a) }
+            Error::Amount(e) => { write!(f, "{}", e) }
+        }
+    }
+}
+
+impl FromStr for Currency {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        CURRENCIES.iter()
+            .position(|(code, _)| code.eq_ignore_ascii_case(s))
+            .map(|index| Currency { index: index as u8 })
+            .ok_or_else(|| UnknownCurrency(s.to_string()))
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.code())
+    }
+}
+
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.amount, self.currency)
+    }
+}
+
+impl<'de> Deserialize<'de> for Currency {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: Deserializer<'de>
+    {
+        let s = String::deserialize(deserializer)?;
+        FromStr::from_str(&s).map_err(de::Error::custom)
+    }
+}
+
+impl Serialize for Currency {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer
+    {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::amount::RoundingMode;
+    use crate::money::{Currency, Error, Money};
+
+    fn money(amount: &str, currency: &str) -> Money {
+        Money::new(amount.parse().unwrap(), currency.parse().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn parse_currency() {
+        let eur: Currency = "EUR".parse().unwrap();
+        assert_eq!(eur.code(), "EUR");
+        assert_eq!(eur.minor_units(), 2);
+        assert_eq!("eur".parse::<Currency>().unwrap(), eur);
+        assert_eq!("JPY".parse::<Currency>().unwrap().minor_units(), 0);
+        assert!(matches!("XYZ".parse::<Currency>(), Err(Error::UnknownCurrency(_))));
+        assert!(matches!("EURO".parse::<Currency>(), Err(Error::UnknownCurrency(_))));
+    }
+
+    #[test]
+    fn precision() {
+        assert_eq!(money("12.5", "EUR").to_string(), "12.5 EUR");
+        assert!(matches!(Money::new("12.505".parse().unwrap(), "EUR".parse().unwrap()), Err(Error::PrecisionTooHigh(_, _))));
+        assert!(matches!(Money::new("1.5".parse().unwrap(), "JPY".parse().unwrap()), Err(Error::PrecisionTooHigh(_, _))));
+        assert_eq!(money("1.505", "BHD").to_string(), "1.505 BHD");
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(money("12.5", "EUR").checked_add(money("0.75", "EUR")).unwrap(), money("13.25", "EUR"));
+        assert_eq!(money("12.5", "EUR").checked_sub(money("0.75", "EUR")).unwrap(), money("11.75", "EUR"));
+        assert_eq!(money("10.01", "USD").checked_mul("0.5".parse().unwrap(), RoundingMode::HalfEven).unwrap(), money("5", "USD"));
+        assert_eq!(money("10.03", "USD").checked_mul("0.5".parse().unwrap(), RoundingMode::HalfEven).unwrap(), money("5.02", "USD"));
+    }
+
+    #[test]
+    fn currency_mismatch() {
+        assert!(matches!(money("1", "EUR").checked_add(money("1", "USD")), Err(Error::CurrencyMismatch(_, _))));
+        assert!(matches!(money("1", "EUR").checked_sub(money("1", "USD")), Err(Error::CurrencyMismatch(_, _))));
+    }
+}
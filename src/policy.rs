@@ -0,0 +1,106 @@
+//! Rules on how low a client's `available` funds may go, see `LedgerPolicy`
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::amount::{Amount, ZERO};
+use crate::ledger::{Balance, TransactionKind};
+use crate::money::Currency;
+
+/// consulted by `Ledger::mutate` whenever a transaction would lower `available`, i.e. on withdrawals and disputes of deposits;
+/// a refused transaction fails with `NegativeBalance`
+pub trait LedgerPolicy: fmt::Debug + Send + Sync {
+    /// whether `kind` may leave `client_id` with `balance` in `currency`
+    fn allows(&self, client_id: u16, kind: TransactionKind, currency: Currency, balance: &Balance) -> bool;
+}
+
+/// `available` never goes negative: disputes of already spent deposits are refused, too
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Strict;
+
+impl LedgerPolicy for Strict {
+    fn allows(&self, _client_id: u16, _kind: TransactionKind, _currency: Currency, balance: &Balance) -> bool {
+        balance.available() >= *ZERO
+    }
+}
+
+/// withdrawals can't overdraw, but a dispute is always held, even if the funds were spent already; the default
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AllowNegativeOnDispute;
+
+impl LedgerPolicy for AllowNegativeOnDispute {
+    fn allows(&self, _client_id: u16, kind: TransactionKind, _currency: Currency, balance: &Balance) -> bool {
+        kind == TransactionKind::Dispute || balance.available() >= *ZERO
+    }
+}
+
+/// `available` may go down to minus the client's limit, in any currency
+#[derive(Debug, Default, Clone)]
+pub struct Overdraft {
+    limit: Amount,
+    limits: HashMap<u16, Amount>,
+}
+
+impl Overdraft {
+    /// `limit` for all clients
+    pub fn new(limit: Amount) -> Self {
+        Overdraft { limit, ..Default::default() }
+    }
+
+    /// `limit` for `client_id` instead of the default one
+    pub fn with_limit(mut self, client_id: u16, limit: Amount) -> Self {
+        self.limits.insert(client_id, limit);
+        self
+    }
+
+    pub fn limit(&self, client_id: u16) -> Amount { self.limits.get(&client_id).copied().unwrap_or(self.limit) }
+}
+
+impl LedgerPolicy for Overdraft {
+    fn allows(&self, client_id: u16, _kind: TransactionKind, _currency: Currency, balance: &Balance) -> bool {
+        balance.available().checked_add(self.limit(client_id)).is_ok_and(|headroom| headroom >= *ZERO)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::ledger::{Ledger, LedgerConfig, Transaction, TransactionError, TransactionKind};
+    use crate::policy::{AllowNegativeOnDispute, LedgerPolicy, Overdraft, Strict};
+
+    fn transaction(id: u64, client_id: u16, kind: TransactionKind, amount: &str) -> Transaction {
+        Transaction::new(id, client_id, kind, amount.parse().unwrap(), Default::default())
+    }
+
+    /// deposits 10, withdraws 12, then disputes the deposit; returns the outcomes of the last two
+    fn outcomes(policy: impl LedgerPolicy + 'static) -> (Result<(), TransactionError>, Result<(), TransactionError>) {
+        let mut ledger = Ledger::new().with_config(LedgerConfig { policy: Arc::new(policy), ..Default::default() });
+        ledger.mutate(transaction(1, 1, TransactionKind::Deposit, "10")).unwrap();
+        ledger.mutate(transaction(2, 2, TransactionKind::Deposit, "10")).unwrap();
+        let withdrawal = ledger.mutate(transaction(3, 1, TransactionKind::Withdrawal, "12")).map(|_| ());
+        let dispute = ledger.mutate(transaction(1, 1, TransactionKind::Dispute, "0")).map(|_| ());
+        (withdrawal, dispute)
+    }
+
+    #[test]
+    fn builtin() {
+        assert_eq!(outcomes(Strict), (Err(TransactionError::NegativeBalance), Ok(())));
+        assert_eq!(outcomes(AllowNegativeOnDispute), (Err(TransactionError::NegativeBalance), Ok(())));
+        // only an overdraft accepts withdrawing 12 out of 10, then refuses the dispute if it goes past the limit
+        assert_eq!(outcomes(Overdraft::new("2".parse().unwrap())), (Ok(()), Err(TransactionError::NegativeBalance)));
+        assert_eq!(outcomes(Overdraft::new("1".parse().unwrap()).with_limit(1, "20".parse().unwrap())), (Ok(()), Ok(())));
+        assert_eq!(outcomes(Overdraft::new("20".parse().unwrap()).with_limit(1, "0".parse().unwrap())), (Err(TransactionError::NegativeBalance), Ok(())));
+    }
+
+    #[test]
+    fn strict_dispute() {
+        let mut ledger = Ledger::new().with_config(LedgerConfig { policy: Arc::new(Strict), ..Default::default() });
+        ledger.mutate(transaction(1, 1, TransactionKind::Deposit, "10")).unwrap();
+        ledger.mutate(transaction(2, 1, TransactionKind::Withdrawal, "8")).unwrap();
+        assert_eq!(ledger.mutate(transaction(1, 1, TransactionKind::Dispute, "0")).unwrap_err(), TransactionError::NegativeBalance);
+        // what's left can still be disputed
+        ledger.mutate(transaction(1, 1, TransactionKind::Dispute, "2")).unwrap();
+        assert_eq!(ledger[&1].balance(Default::default()).held(), "2".parse().unwrap());
+    }
+}
@@ -0,0 +1,421 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::mem::size_of;
+
+use serde::{Deserialize, Serialize};
+
+use crate::amount::Amount;
+use crate::ledger::{Transaction, TransactionKind, TransactionState};
+use crate::ledger::TransactionState::{ChargedBack, Disputed, Processed, Resolved};
+use crate::money::Currency;
+use crate::TransactionKind::{Chargeback, Deposit, Dispute, Resolve, Withdrawal};
+
+/// a deposit or withdrawal kept for disputes, with its dispute state
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub struct StoredTransaction {
+    transaction: Transaction,
+    state: TransactionState,
+}
+
+impl StoredTransaction {
+    pub fn new(transaction: Transaction, state: TransactionState) -> Self {
+        StoredTransaction { transaction, state }
+    }
+
+    pub fn transaction(&self) -> &Transaction { &self.transaction }
+    pub fn state(&self) -> TransactionState { self.state }
+}
+
+/// where a `Ledger` keeps the deposits and withdrawals clients can dispute; stores that don't live in memory may fail with I/O errors
+pub trait TransactionStore {
+    fn get(&self, id: u64) -> io::Result<Option<StoredTransaction>>;
+    /// adds `stored`, replacing any transaction with the same id
+    fn insert(&mut self, stored: StoredTransaction) -> io::Result<()>;
+    /// returns false if there's no transaction with `id`
+    fn set_state(&mut self, id: u64, state: TransactionState) -> io::Result<bool>;
+    /// removes the transaction with `id`, if any
+    fn remove(&mut self, id: u64) -> io::Result<()>;
+    /// all transactions, in no particular order
+    fn iter(&self) -> Box<dyn Iterator<Item=io::Result<StoredTransaction>> + '_>;
+    /// in-memory copy
+    fn to_compact(&self) -> io::Result<CompactStore> { self.iter().collect() }
+}
+
+// `meta` packs the kind into the low 3 bits and the state into the next 2; index is the packed value
+const KINDS: [TransactionKind; 5] = [Deposit, Withdrawal, Dispute, Resolve, Chargeback];
+const STATES: [TransactionState; 4] = [Processed, Disputed, Resolved, ChargedBack];
+// `meta` of a removed transaction, not a valid packed kind and state
+pub(crate) const REMOVED: u8 = 0xFF;
+
+/// columnar store of `StoredTransaction`s, 20 bytes per transaction. Ids are expected to be mostly increasing:
+/// those are appended to the columns and found by interpolation, while the rest go to a regular, much bigger, map.
+/// Removed transactions are only marked as such, until they make up half of the columns
+#[derive(Debug, Default, Clone)]
+pub struct CompactStore {
+    ids: Vec<u64>,
+    clients: Vec<u16>,
+    amounts: Vec<Amount>,
+    currencies: Vec<Currency>,
+    meta: Vec<u8>,
+    // ids lower than the last one in the columns
+    overflow: HashMap<u64, StoredTransaction>,
+    // entries of the columns marked `REMOVED`
+    removed: usize,
+}
+
+impl CompactStore {
+    pub fn new() -> Self {
+        CompactStore { ..Default::default() }
+    }
+
+    pub fn len(&self) -> usize { self.ids.len() - self.removed + self.overflow.len() }
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+
+    pub fn get(&self, id: u64) -> Option<StoredTransaction> {
+        match self.position(id) {
+            Some(index) => { (self.meta[index] != REMOVED).then(|| self.at(index)) }
+            None => { self.overflow.get(&id).copied() }
+        }
+    }
+
+    /// adds `stored`, replacing any transaction with the same id
+    pub fn insert(&mut self, stored: StoredTransaction) {
+        let transaction = stored.transaction;
+        if let Some(index) = self.position(transaction.id()) {
+            if self.meta[index] == REMOVED { self.removed -= 1; }
+            self.clients[index] = transaction.client_id();
+            self.amounts[index] = transaction.amount();
+            self.currencies[index] = transaction.currency();
+            self.meta[index] = pack(transaction.kind(), stored.state);
+        } else if self.ids.last().map_or(true, |&last| transaction.id() > last) {
+            self.ids.push(transaction.id());
+            self.clients.push(transaction.client_id());
+            self.amounts.push(transaction.amount());
+            self.currencies.push(transaction.currency());
+            self.meta.push(pack(transaction.kind(), stored.state));
+        } else {
+            self.overflow.insert(transaction.id(), stored);
+        }
+    }
+
+    /// returns false if there's no transaction with `id`
+    pub fn set_state(&mut self, id: u64, state: TransactionState) -> bool {
+        match self.position(id) {
+            Some(index) if self.meta[index] == REMOVED => { false }
+            Some(index) => { self.meta[index] = pack(unpack(self.meta[index]).unwrap().0, state); true }
+            None => { self.overflow.get_mut(&id).map(|stored| stored.state = state).is_some() }
+        }
+    }
+
+    /// removes the transaction with `id`, if any
+    pub fn remove(&mut self, id: u64) {
+        match self.position(id) {
+            Some(index) if self.meta[index] != REMOVED => {
+                self.meta[index] = REMOVED;
+                self.removed += 1;
+                if self.removed * 2 > self.ids.len() { self.compact(); }
+            }
+            Some(_) => {}
+            None => { self.overflow.remove(&id); }
+        }
+    }
+
+    /// all transactions, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item=StoredTransaction> + '_ {
+        (0..self.ids.len()).filter(|&index| self.meta[index] != REMOVED).map(|index| self.at(index)).chain(self.overflow.values().copied())
+    }
+
+    /// moves the transactions of `stores`, with disjoint ids, into one store, in a single pass: their columns are in id order
+    /// already, and only the overflow needs sorting
+    pub fn merge(stores: Vec<CompactStore>) -> CompactStore {
+        let mut sources: Vec<_> = stores.iter().flat_map(|store| {
+            let mut overflow: Vec<StoredTransaction> = store.overflow.values().copied().collect();
+            overflow.sort_by_key(|t| t.transaction.id());
+            let columns = (0..store.ids.len()).filter(|&index| store.meta[index] != REMOVED).map(|index| store.at(index));
+            [Box::new(columns) as Box<dyn Iterator<Item=StoredTransaction>>, Box::new(overflow.into_iter())]
+        }).map(Iterator::peekable).collect();
+        let mut merged = CompactStore::new();
+        // a few sources per shard, so a linear scan for the lowest id beats a heap
+        while let Some((_, index)) = sources.iter_mut().enumerate().filter_map(|(index, source)| source.peek().map(|t| (t.transaction.id(), index))).min() {
+            merged.insert(sources[index].next().unwrap());
+        }
+        merged
+    }
+
+    /// approximate heap usage, in bytes
+    pub fn memory_bytes(&self) -> usize {
+        self.ids.capacity() * size_of::<u64>()
+            + self.clients.capacity() * size_of::<u16>()
+            + self.amounts.capacity() * size_of::<Amount>()
+            + self.currencies.capacity() * size_of::<Currency>()
+            + self.meta.capacity()
+            // hashbrown: key, value and a control byte per bucket
+            + self.overflow.capacity() * (size_of::<(u64, StoredTransaction)>() + 1)
+    }
+
+    // ids are usually dense, so guess the position by interpolation, then gallop around it: a plain binary search over
+    // a billion ids is a cache miss at every step
+    fn position(&self, id: u64) -> Option<usize> {
+        let (first, last) = (*self.ids.first()?, *self.ids.last()?);
+        if id < first || id > last { return None; }
+        let len = self.ids.len();
+        let guess = ((id - first) as u128 * (len - 1) as u128 / (last - first).max(1) as u128) as usize;
+
+        let (mut lo, mut hi, mut step) = (guess, guess + 1, 1);
+        while lo > 0 && self.ids[lo] > id {
+            hi = lo;
+            lo = lo.saturating_sub(step);
+            step *= 2;
+        }
+        while hi < len && self.ids[hi - 1] < id {
+            lo = hi;
+            hi = (hi + step).min(len);
+            step *= 2;
+        }
+        self.ids[lo..hi].binary_search(&id).ok().map(|index| lo + index)
+    }
+
+    /// drops removed entries from the columns; capacity is kept for the transactions to come
+    fn compact(&mut self) {
+        let mut kept = 0;
+        for index in 0..self.ids.len() {
+            if self.meta[index] == REMOVED { continue; }
+            self.ids[kept] = self.ids[index];
+            self.clients[kept] = self.clients[index];
+            self.amounts[kept] = self.amounts[index];
+            self.currencies[kept] = self.currencies[index];
+            self.meta[kept] = self.meta[index];
+            kept += 1;
+        }
+        self.ids.truncate(kept);
+        self.clients.truncate(kept);
+        self.amounts.truncate(kept);
+        self.currencies.truncate(kept);
+        self.meta.truncate(kept);
+        self.removed = 0;
+    }
+
+    fn at(&self, index: usize) -> StoredTransaction {
+        let (kind, state) = unpack(self.meta[index]).unwrap();
+        let transaction = Transaction::new(self.ids[index], self.clients[index], kind, self.amounts[index], self.currencies[index]);
+        StoredTransaction { transaction, state }
+    }
+}
+
+/// never fails
+impl TransactionStore for CompactStore {
+    fn get(&self, id: u64) -> io::Result<Option<StoredTransaction>> { Ok(CompactStore::get(self, id)) }
+    fn insert(&mut self, stored: StoredTransaction) -> io::Result<()> { CompactStore::insert(self, stored); Ok(()) }
+    fn set_state(&mut self, id: u64, state: TransactionState) -> io::Result<bool> { Ok(CompactStore::set_state(self, id, state)) }
+    fn remove(&mut self, id: u64) -> io::Result<()> { CompactStore::remove(self, id); Ok(()) }
+    fn iter(&self) -> Box<dyn Iterator<Item=io::Result<StoredTransaction>> + '_> { Box::new(CompactStore::iter(self).map(Ok)) }
+    fn to_compact(&self) -> io::Result<CompactStore> { Ok(self.clone()) }
+}
+
+/// sorts by id first, so everything ends up in the columns
+impl FromIterator<StoredTransaction> for CompactStore {
+    fn from_iter<I: IntoIterator<Item=StoredTransaction>>(iter: I) -> Self {
+        let mut transactions: Vec<StoredTransaction> = iter.into_iter().collect();
+        transactions.sort_by_key(|t| t.transaction.id());
+        let mut store = CompactStore::new();
+        for stored in transactions {
+            store.insert(stored);
+        }
+        store
+    }
+}
+
+// ids split into chunks of 2^16 by their high bits; a chunk holding more than this many ids is a bitmap, as that's smaller
+const ARRAY_MAX: usize = 4096;
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+enum Chunk {
+    // sorted low bits
+    Array(Vec<u16>),
+    Bitmap(Vec<u64>),
+    Full,
+}
+
+/// exact set of ids, in the fashion of a roaring bitmap: at most 2 bytes per id when sparse, 1 bit when dense,
+/// and next to nothing for runs of 2^16 consecutive ids
+#[derive(Debug, Default, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct IdSet {
+    chunks: BTreeMap<u64, Chunk>,
+}
+
+impl IdSet {
+    pub fn new() -> Self {
+        IdSet { ..Default::default() }
+    }
+
+    pub fn contains(&self, id: u64) -> bool {
+        let low = id as u16;
+        match self.chunks.get(&(id >> 16)) {
+            None => { false }
+            Some(Chunk::Array(lows)) => { lows.binary_search(&low).is_ok() }
+            Some(Chunk::Bitmap(words)) => { words[low as usize / 64] & 1 << (low % 64) != 0 }
+            Some(Chunk::Full) => { true }
+        }
+    }
+
+    /// returns false if `id` was already there
+    pub fn insert(&mut self, id: u64) -> bool {
+        let low = id as u16;
+        let chunk = self.chunks.entry(id >> 16).or_insert(Chunk::Array(Vec::new()));
+        match chunk {
+            Chunk::Array(lows) => {
+                let Err(index) = lows.binary_search(&low) else { return false; };
+                lows.insert(index, low);
+                if lows.len() > ARRAY_MAX {
+                    let mut words = vec![0; 1 << 10];
+                    for low in lows.iter() {
+                        words[*low as usize / 64] |= 1 << (low % 64);
+                    }
+                    *chunk = Chunk::Bitmap(words);
+                }
+            }
+            Chunk::Bitmap(words) => {
+                let word = &mut words[low as usize / 64];
+                if *word & 1 << (low % 64) != 0 { return false; }
+                *word |= 1 << (low % 64);
+                if *word == u64::MAX && words.iter().all(|&w| w == u64::MAX) { *chunk = Chunk::Full; }
+            }
+            Chunk::Full => { return false; }
+        }
+        true
+    }
+}
+
+pub(crate) fn pack(kind: TransactionKind, state: TransactionState) -> u8 {
+    let kind = KINDS.iter().position(|&k| k == kind).unwrap() as u8;
+    let state = STATES.iter().position(|&s| s == state).unwrap() as u8;
+    kind | state << 3
+}
+
+/// `None` if `meta` is not a packed kind and state
+pub(crate) fn unpack(meta: u8) -> Option<(TransactionKind, TransactionState)> {
+    Some((*KINDS.get((meta & 0b111) as usize)?, *STATES.get((meta >> 3) as usize)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ledger::{Transaction, TransactionKind, TransactionState};
+    use crate::store::{Chunk, CompactStore, IdSet, StoredTransaction};
+
+    fn stored(id: u64, kind: TransactionKind, amount: &str) -> StoredTransaction {
+        let transaction = Transaction::new(id, (id % 7) as u16, kind, amount.parse().unwrap(), "EUR".parse().unwrap());
+        StoredTransaction::new(transaction, TransactionState::Processed)
+    }
+
+    #[test]
+    fn insert_get() {
+        let mut store = CompactStore::new();
+        for id in [1, 2, 5, 3, 8, 4] {
+            store.insert(stored(id, TransactionKind::Withdrawal, "1.25"));
+        }
+        assert_eq!(store.len(), 6);
+        assert_eq!(store.overflow.len(), 2);
+        for id in [1, 2, 3, 4, 5, 8] {
+            assert_eq!(store.get(id), Some(stored(id, TransactionKind::Withdrawal, "1.25")));
+        }
+        assert_eq!(store.get(6), None);
+
+        // replaces, in both tiers
+        store.insert(stored(5, TransactionKind::Deposit, "3"));
+        store.insert(stored(3, TransactionKind::Deposit, "3"));
+        assert_eq!(store.len(), 6);
+        assert_eq!(store.get(5), Some(stored(5, TransactionKind::Deposit, "3")));
+        assert_eq!(store.get(3), Some(stored(3, TransactionKind::Deposit, "3")));
+    }
+
+    #[test]
+    fn set_state() {
+        let mut store = CompactStore::new();
+        store.insert(stored(2, TransactionKind::Deposit, "1"));
+        store.insert(stored(1, TransactionKind::Withdrawal, "1"));
+        assert!(store.set_state(2, TransactionState::ChargedBack));
+        assert!(store.set_state(1, TransactionState::Disputed));
+        assert!(!store.set_state(3, TransactionState::Disputed));
+        assert_eq!(store.get(2).unwrap().state(), TransactionState::ChargedBack);
+        assert_eq!(store.get(2).unwrap().transaction().kind(), TransactionKind::Deposit);
+        assert_eq!(store.get(1).unwrap().state(), TransactionState::Disputed);
+    }
+
+    #[test]
+    fn remove() {
+        let mut store = CompactStore::new();
+        for id in [1, 2, 3, 4, 0] {
+            store.insert(stored(id, TransactionKind::Deposit, "1"));
+        }
+        store.remove(2);
+        store.remove(0);
+        store.remove(7);
+        assert_eq!(store.len(), 3);
+        assert_eq!(store.get(2), None);
+        assert!(!store.set_state(2, TransactionState::Disputed));
+        assert_eq!(store.iter().count(), 3);
+
+        // reinserted in place
+        store.insert(stored(2, TransactionKind::Withdrawal, "1"));
+        assert_eq!(store.get(2), Some(stored(2, TransactionKind::Withdrawal, "1")));
+
+        // compacted once half of the columns is removed
+        store.remove(1);
+        store.remove(2);
+        store.remove(3);
+        assert_eq!((store.ids.len(), store.removed), (1, 0));
+        assert_eq!(store.iter().collect::<Vec<_>>(), vec![stored(4, TransactionKind::Deposit, "1")]);
+        assert_eq!(store.get(4), Some(stored(4, TransactionKind::Deposit, "1")));
+    }
+
+    #[test]
+    fn merge() {
+        let even: CompactStore = (0..10).filter(|id| id % 2 == 0).map(|id| stored(id, TransactionKind::Deposit, "1")).collect();
+        let mut odd: CompactStore = (0..10).filter(|id| id % 2 == 1).map(|id| stored(id, TransactionKind::Deposit, "1")).collect();
+        // out of order, so in the overflow
+        odd.insert(stored(20, TransactionKind::Deposit, "1"));
+        odd.insert(stored(12, TransactionKind::Deposit, "1"));
+        odd.insert(stored(10, TransactionKind::Deposit, "1"));
+        odd.remove(3);
+        let merged = CompactStore::merge(vec![even, odd, CompactStore::new()]);
+        assert_eq!(merged.len(), 12);
+        assert!(merged.overflow.is_empty());
+        assert!((0..13).all(|id| merged.get(id).is_some() == (id != 3 && id != 11)));
+        assert!(merged.get(20).is_some());
+    }
+
+    #[test]
+    fn sparse_ids() {
+        let ids: Vec<u64> = (0..1000u64).map(|i| i * i + if i > 500 { 1_000_000_000 } else { 0 }).collect();
+        let store: CompactStore = ids.iter().map(|&id| stored(id, TransactionKind::Deposit, "1")).collect();
+        assert!(ids.iter().all(|&id| store.get(id).is_some_and(|s| s.transaction().id() == id)));
+        assert!(ids.iter().all(|&id| store.get(id + 1).is_none() || ids.contains(&(id + 1))));
+        assert_eq!(store.get(u64::MAX), None);
+    }
+
+    #[test]
+    fn id_set() {
+        let mut ids = IdSet::new();
+        let sparse: Vec<u64> = (0..100u64).map(|i| i * i * 7919).collect();
+        assert!(sparse.iter().all(|&id| ids.insert(id)));
+        assert!(!ids.insert(7919));
+        assert!(sparse.iter().all(|&id| ids.contains(id)));
+        assert!(!ids.contains(7918) && !ids.contains(7920) && !ids.contains(u64::MAX));
+
+        // dense chunks go from array, to bitmap, to full
+        let base = 5 << 16;
+        for id in base..base + 5000 {
+            ids.insert(id);
+        }
+        assert!(matches!(ids.chunks[&5], Chunk::Bitmap(_)));
+        for id in (base..base + (1 << 16)).rev() {
+            ids.insert(id);
+        }
+        assert_eq!(ids.chunks[&5], Chunk::Full);
+        assert!(ids.contains(base) && ids.contains(base + 0xFFFF) && !ids.contains(base + (1 << 16)));
+        assert!(sparse.iter().all(|&id| ids.contains(id)));
+
+        let json = serde_json::to_string(&ids).unwrap();
+        assert_eq!(serde_json::from_str::<IdSet>(&json).unwrap(), ids);
+    }
+}
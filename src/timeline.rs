@@ -0,0 +1,106 @@
+use std::io;
+
+use crate::ledger::{Ledger, Transaction, TransactionError};
+use crate::store::TransactionStore;
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    line: u64,
+    timestamp: u64,
+    transaction: Transaction,
+}
+
+/// accepted transactions of a run with periodic copies of the ledger, to tell the state at any input line or timestamp
+/// by replaying from the closest earlier copy. Every copy is a full, in-memory ledger, so `interval` should grow with the size of the ledger
+#[derive(Debug)]
+pub struct Timeline {
+    interval: usize,
+    entries: Vec<Entry>,
+    // ledger after the first n entries, the first one being the starting state
+    snapshots: Vec<(usize, Ledger)>,
+}
+
+impl Timeline {
+    /// starts from `ledger`, copying it after every `interval` transactions
+    pub fn new<S: TransactionStore>(ledger: &Ledger<S>, interval: usize) -> io::Result<Self> {
+        Ok(Timeline { interval: interval.max(1), entries: Vec::new(), snapshots: vec![(0, ledger.to_memory()?)] })
+    }
+
+    /// call after `transaction`, read from input `line`, has been accepted by `ledger`. It is stamped with the ledger's clock, not its own
+    /// timestamp, since refused lines move the clock too and the replay has to evict the same transactions
+    pub fn record<S: TransactionStore>(&mut self, line: u64, transaction: Transaction, ledger: &Ledger<S>) -> io::Result<()> {
+        self.entries.push(Entry { line, timestamp: ledger.clock(), transaction });
+        if self.entries.len() % self.interval == 0 {
+            self.snapshots.push((self.entries.len(), ledger.to_memory()?));
+        }
+        Ok(())
+    }
+
+    /// state after all input lines up to and including `line`; fails only if replaying an accepted transaction does,
+    /// e.g. with a `LedgerPolicy` that isn't deterministic
+    pub fn at_line(&self, line: u64) -> Result<Ledger, TransactionError> {
+        self.at(self.entries.partition_point(|e| e.line <= line))
+    }
+
+    /// state after all transactions accepted up to and including `timestamp`, as told by the ledger's clock, which never goes back
+    pub fn at_timestamp(&self, timestamp: u64) -> Result<Ledger, TransactionError> {
+        self.at(self.entries.partition_point(|e| e.timestamp <= timestamp))
+    }
+
+    /// state after the first `count` entries
+    fn at(&self, count: usize) -> Result<Ledger, TransactionError> {
+        let (start, snapshot) = &self.snapshots[self.snapshots.partition_point(|(start, _)| *start <= count) - 1];
+        let mut ledger = snapshot.clone();
+        for entry in &self.entries[*start..count] {
+            ledger.advance(entry.timestamp)?;
+            ledger.mutate(entry.transaction)?;
+        }
+        Ok(ledger)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ledger::{Ledger, Transaction, TransactionKind};
+    use crate::timeline::Timeline;
+
+    fn transaction(id: u64, client_id: u16, kind: TransactionKind, amount: &str) -> Transaction {
+        Transaction::new(id, client_id, kind, amount.parse().unwrap(), Default::default())
+    }
+
+    #[test]
+    fn at_line() {
+        let transactions = [
+            transaction(1, 1, TransactionKind::Deposit, "10"),
+            transaction(2, 1, TransactionKind::Deposit, "5"),
+            transaction(1, 1, TransactionKind::Dispute, "0"),
+            transaction(3, 2, TransactionKind::Deposit, "1"),
+            transaction(1, 1, TransactionKind::Chargeback, "0"),
+        ];
+
+        for interval in [1, 2, 100] {
+            let mut ledger = Ledger::new();
+            let mut timeline = Timeline::new(&ledger, interval).unwrap();
+            let mut states = vec![ledger.clone()];
+            // every other line is refused, or malformed
+            for (i, transaction) in transactions.iter().enumerate() {
+                ledger.advance(100 * i as u64).unwrap();
+                ledger.mutate(*transaction).unwrap();
+                timeline.record(2 * i as u64 + 2, *transaction, &ledger).unwrap();
+                states.push(ledger.clone());
+            }
+
+            assert_eq!(*timeline.at_line(0).unwrap(), *states[0]);
+            assert_eq!(*timeline.at_line(1).unwrap(), *states[0]);
+            for (i, state) in states.iter().enumerate().skip(1) {
+                assert_eq!(*timeline.at_line(2 * i as u64).unwrap(), **state);
+                assert_eq!(*timeline.at_line(2 * i as u64 + 1).unwrap(), **state);
+                assert_eq!(*timeline.at_timestamp(100 * (i as u64 - 1) + 50).unwrap(), **state);
+            }
+            // right before the chargeback
+            assert_eq!(timeline.at_line(9).unwrap()[&1].balance(Default::default()).held(), "10".parse().unwrap());
+            assert!(!timeline.at_line(9).unwrap()[&1].locked());
+            assert!(timeline.at_line(10).unwrap()[&1].locked());
+        }
+    }
+}